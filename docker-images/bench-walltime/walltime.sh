@@ -2,13 +2,45 @@
 
 set -eu
 
-IFS=','; for file in ${FILE_LIST} ; do
+IFS=','
+
+total=0
+for file in ${FILE_LIST} ; do
+    total=$((total + 1))
+done
+
+# Results of a previous attempt must not pass for samples of this one.
+rm -f /data/*.json
+
+count=0
+status=0
+for file in ${FILE_LIST} ; do
+    name=$(basename $file .typ)
+
+    # A failed sample does not stop the others, such that the samples that
+    # finished are still reported. The first failure is the exit status.
+    code=0
     timeout ${TIMEOUT} /bin/cobench \
         measure \
         -n ${RUNS} \
         -w ${WARMUPS} \
         -S ${WORK} \
         -s ${SLEEP} \
-        --export-path /data/$(basename $file .typ).json \
-        "/typster/target/release/typst compile --font-path $(dirname $file) ${file} /dev/null"
-done
\ No newline at end of file
+        --export-path /data/${name}.json \
+        "/typster/target/release/typst compile --font-path $(dirname $file) ${file} /dev/null" \
+        || code=$?
+
+    if [ ${code} -ne 0 ]; then
+        echo "sample ${name} failed with code ${code}" >&2
+        rm -f /data/${name}.json
+        if [ ${status} -eq 0 ]; then
+            status=${code}
+        fi
+    fi
+
+    # Lets the runner report the progress of the benchmark.
+    count=$((count + 1))
+    echo "typster-progress ${count}/${total} ${name}"
+done
+
+exit ${status}
@@ -2,4 +2,7 @@
 
 set -eu
 
-timeout ${TIMEOUT} cargo build --release -p typst-cli
\ No newline at end of file
+# Lets the runner record the toolchain with the results.
+echo "typster-rustc $(rustc --version)"
+
+timeout ${TIMEOUT} cargo build --release -p typst-cli
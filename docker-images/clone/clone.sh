@@ -4,10 +4,45 @@ set -eu
 
 # Set ownership
 git config --global --add safe.directory /typster
+git config --global --add safe.directory /mirror
 
 # Set the default branch name to avoid stderr output
 git config --global init.defaultBranch main
 
+# Update the bare mirror of the repository instead of cloning
+if [ "${MODE:-clone}" = "mirror" ]; then
+    cd /mirror
+
+    # Initialize the mirror
+    if [ ! -f HEAD ]; then
+        git init --bare
+        git remote add --mirror=fetch origin $REPO_URL
+    fi
+
+    # Nothing to do if the commit is already mirrored
+    if git cat-file -e "${COMMIT}^{commit}" 2>/dev/null; then
+        echo "Commit ${COMMIT} is already mirrored"
+        exit 0
+    fi
+
+    # Fetch the new history of every branch
+    timeout ${TIMEOUT} git fetch origin
+
+    # Fetch the commit by itself if it is not on any branch, keeping a
+    # reference to it so that it is never garbage collected
+    if ! git cat-file -e "${COMMIT}^{commit}" 2>/dev/null; then
+        timeout ${TIMEOUT} git fetch origin "${COMMIT}:refs/typster/${COMMIT}"
+    fi
+
+    exit 0
+fi
+
+# Clone from the mirror if there is one
+ORIGIN=$REPO_URL
+if [ -f /mirror/HEAD ]; then
+    ORIGIN=file:///mirror
+fi
+
 # go into the directory
 cd /typster
 
@@ -15,10 +50,13 @@ cd /typster
 git init || true
 
 # Add the remote origin
-timeout ${TIMEOUT} git remote add origin $REPO_URL || true
+timeout ${TIMEOUT} git remote add origin $ORIGIN || true
 
 # Fetch the commit
 timeout ${TIMEOUT} git fetch --depth 1 origin $COMMIT || true
 
 # Checkout
-timeout ${TIMEOUT} git checkout $COMMIT
\ No newline at end of file
+timeout ${TIMEOUT} git checkout $COMMIT
+
+# Report the committer time, which orders the commits of a branch
+echo "typster-committed $(git log -1 --format=%ct)"
\ No newline at end of file
@@ -2,4 +2,6 @@
 
 set -eu
 
-timeout ${TIMEOUT} cargo fetch
\ No newline at end of file
+# Use the crates that are already in the registry if possible, such that a
+# warm registry never touches the network
+timeout ${TIMEOUT} cargo fetch --offline || timeout ${TIMEOUT} cargo fetch
\ No newline at end of file
@@ -0,0 +1,215 @@
+//! Change-point detection over the history of a metric.
+//!
+//! A series is split by binary segmentation, as in E-divisive: the split that
+//! maximizes the weighted squared difference of the means of both sides is
+//! kept if a permutation test finds it significant, and both sides are split
+//! again until no significant split is left. The permutations use a fixed
+//! seed such that analyzing the same series twice gives the same result.
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::statistics::bootstrap_rng;
+
+/// How change points are searched for.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChangePointSettings {
+    /// The significance level of the permutation test
+    #[serde(default = "default_alpha")]
+    pub alpha: f64,
+
+    /// The number of permutations of the test
+    #[serde(default = "default_permutations")]
+    pub permutations: usize,
+
+    /// The minimum number of points on either side of a change point
+    #[serde(default = "default_min_segment")]
+    pub min_segment: usize,
+}
+
+impl Default for ChangePointSettings {
+    fn default() -> Self {
+        Self {
+            alpha: default_alpha(),
+            permutations: default_permutations(),
+            min_segment: default_min_segment(),
+        }
+    }
+}
+
+/// A point of a series from which on its mean changed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChangePoint {
+    /// The index of the first point after the change
+    pub index: usize,
+
+    /// The mean of the segment before the change
+    pub before: f64,
+
+    /// The mean of the segment from the change on
+    pub after: f64,
+
+    /// The p-value of the permutation test
+    pub p_value: f64,
+}
+
+impl ChangePoint {
+    /// The relative change of the mean, such as `0.02` for 2% more
+    pub fn change(&self) -> f64 {
+        self.after / self.before - 1.0
+    }
+
+    /// The confidence that the change is real
+    pub fn confidence(&self) -> f64 {
+        1.0 - self.p_value
+    }
+}
+
+/// Returns the significant change points of a series, in order
+pub fn detect(series: &[f64], settings: &ChangePointSettings) -> Vec<ChangePoint> {
+    let mut splits = Vec::new();
+    segment(series, 0, settings, &mut splits);
+    splits.sort_by_key(|(index, _)| *index);
+
+    // The means are those of the final segments around each change point,
+    // rather than of the halves in which it was found.
+    let bounds = std::iter::once(0)
+        .chain(splits.iter().map(|(index, _)| *index))
+        .chain(std::iter::once(series.len()))
+        .collect::<Vec<_>>();
+
+    splits
+        .iter()
+        .enumerate()
+        .map(|(i, &(index, p_value))| ChangePoint {
+            index,
+            before: mean(&series[bounds[i]..index]),
+            after: mean(&series[index..bounds[i + 2]]),
+            p_value,
+        })
+        .collect()
+}
+
+/// Splits a segment of a series at its most significant change point, if
+/// any, and recurses into both halves
+fn segment(
+    series: &[f64],
+    offset: usize,
+    settings: &ChangePointSettings,
+    splits: &mut Vec<(usize, f64)>,
+) {
+    let Some((index, statistic)) = best_split(series, settings.min_segment) else {
+        return;
+    };
+
+    let mut rng = bootstrap_rng();
+    let mut shuffled = series.to_vec();
+    let mut exceeded = 0;
+    for _ in 0..settings.permutations {
+        shuffled.shuffle(&mut rng);
+        if best_split(&shuffled, settings.min_segment).is_some_and(|(_, s)| s >= statistic) {
+            exceeded += 1;
+        }
+    }
+
+    let p_value = (exceeded + 1) as f64 / (settings.permutations + 1) as f64;
+    if p_value >= settings.alpha {
+        return;
+    }
+
+    splits.push((offset + index, p_value));
+    segment(&series[..index], offset, settings, splits);
+    segment(&series[index..], offset + index, settings, splits);
+}
+
+/// Returns the split of a series that maximizes the weighted squared
+/// difference of the means of both sides, and that difference
+fn best_split(series: &[f64], min_segment: usize) -> Option<(usize, f64)> {
+    let n = series.len();
+    let min_segment = min_segment.max(1);
+    if n < 2 * min_segment {
+        return None;
+    }
+
+    let total = series.iter().sum::<f64>();
+    let mut left = series[..min_segment - 1].iter().sum::<f64>();
+    let mut best: Option<(usize, f64)> = None;
+    for index in min_segment..=n - min_segment {
+        left += series[index - 1];
+        let (l, r) = (index as f64, (n - index) as f64);
+        let difference = left / l - (total - left) / r;
+        let statistic = l * r / n as f64 * difference * difference;
+        if best.is_none_or(|(_, s)| statistic > s) {
+            best = Some((index, statistic));
+        }
+    }
+
+    best
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn default_alpha() -> f64 {
+    0.01
+}
+
+fn default_permutations() -> usize {
+    199
+}
+
+fn default_min_segment() -> usize {
+    3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small, fixed noise around zero
+    const NOISE: [f64; 5] = [0.1, -0.2, 0.05, 0.15, -0.1];
+
+    fn noisy(level: f64, len: usize) -> Vec<f64> {
+        (0..len).map(|i| level + NOISE[i % NOISE.len()]).collect()
+    }
+
+    #[test]
+    fn detect_finds_a_step() {
+        let mut series = noisy(10.0, 12);
+        series.extend(noisy(12.0, 8));
+
+        let changes = detect(&series, &ChangePointSettings::default());
+        assert_eq!(changes.len(), 1, "{:?}", changes);
+        assert_eq!(changes[0].index, 12);
+        assert!((changes[0].before - 10.0).abs() < 0.1);
+        assert!((changes[0].after - 12.0).abs() < 0.1);
+        assert!(changes[0].change() > 0.19 && changes[0].change() < 0.21);
+        assert!(changes[0].p_value < 0.01);
+    }
+
+    #[test]
+    fn detect_finds_two_steps() {
+        let mut series = noisy(10.0, 8);
+        series.extend(noisy(15.0, 8));
+        series.extend(noisy(5.0, 8));
+
+        let changes = detect(&series, &ChangePointSettings::default());
+        let indices = changes.iter().map(|c| c.index).collect::<Vec<_>>();
+        assert_eq!(indices, [8, 16]);
+    }
+
+    #[test]
+    fn detect_ignores_a_flat_series() {
+        let series = noisy(10.0, 30);
+        assert_eq!(detect(&series, &ChangePointSettings::default()), []);
+        assert_eq!(detect(&[5.0; 20], &ChangePointSettings::default()), []);
+    }
+
+    #[test]
+    fn detect_needs_enough_points() {
+        let settings = ChangePointSettings::default();
+        assert_eq!(detect(&[], &settings), []);
+        assert_eq!(detect(&[1.0, 1.0, 9.0, 9.0, 9.0], &settings), []);
+    }
+}
@@ -0,0 +1,424 @@
+//! A/B comparison of the samples of two benchmark runs.
+//!
+//! Two runs are compared by the ratio of their medians, with a bootstrap
+//! confidence interval of the ratio, and by a Mann–Whitney U test. Changes
+//! of times are reported as faster or slower, and changes of other metrics,
+//! such as memory, as lower or higher.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    statistics::{
+        bootstrap_rng, interval, percentile, resample, resample_median, Interval,
+        BOOTSTRAP_RESAMPLES,
+    },
+    BenchResult, BuildVariant, SampleMetric,
+};
+
+/// When a difference between two runs counts as a change.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Thresholds {
+    /// The significance level, the accepted probability of reporting a
+    /// change where there is none
+    pub alpha: f64,
+
+    /// The relative change below which a difference is not reported, such
+    /// as `0.01` for 1%
+    pub min_change: f64,
+
+    /// The test that decides whether a difference is significant
+    pub test: Test,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            alpha: 0.05,
+            min_change: 0.01,
+            test: Test::MannWhitney,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Checks that the significance level is between 0 and 1 and that the
+    /// minimum change is not negative
+    pub fn validate(&self) -> Result<(), String> {
+        let alpha_valid = self.alpha > 0.0 && self.alpha < 1.0;
+        if !alpha_valid {
+            return Err("the significance level must be between 0 and 1".into());
+        }
+
+        if self.min_change.is_nan() || self.min_change < 0.0 {
+            return Err("the minimum change must not be negative".into());
+        }
+
+        Ok(())
+    }
+}
+
+/// A test of whether two runs differ.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Test {
+    /// The two-sided Mann–Whitney U test must reject equal distributions
+    MannWhitney,
+
+    /// The bootstrap confidence interval of the ratio must exclude 1
+    Bootstrap,
+}
+
+/// The outcome of a comparison.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Faster,
+    Slower,
+    Lower,
+    Higher,
+    NoChange,
+}
+
+/// How the samples of a candidate compare to those of a baseline.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Comparison {
+    /// The median of the baseline
+    pub baseline: f64,
+
+    /// The median of the candidate
+    pub candidate: f64,
+
+    /// The ratio of the median of the candidate to that of the baseline
+    pub ratio: f64,
+
+    /// The bootstrap confidence interval of the ratio, at a confidence of
+    /// `1 - alpha`
+    pub ratio_ci: Interval,
+
+    /// The p-value of the two-sided Mann–Whitney U test
+    pub p_value: f64,
+
+    pub verdict: Verdict,
+}
+
+/// The comparison of one metric of one sample of a build variant.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SampleComparison {
+    pub variant: BuildVariant,
+    pub sample: String,
+    pub metric: SampleMetric,
+    pub comparison: Comparison,
+}
+
+impl Comparison {
+    /// Compares the samples of a metric of a candidate to those of a
+    /// baseline, returning `None` if either has no samples or the median of
+    /// the baseline is zero
+    pub fn of(
+        baseline: &[f64],
+        candidate: &[f64],
+        metric: SampleMetric,
+        thresholds: &Thresholds,
+    ) -> Option<Self> {
+        if baseline.is_empty() || candidate.is_empty() {
+            return None;
+        }
+
+        let base_median = median(baseline);
+        let candidate_median = median(candidate);
+        if base_median == 0.0 {
+            return None;
+        }
+
+        let ratio = candidate_median / base_median;
+        let ratio_ci = ratio_interval(baseline, candidate, 1.0 - thresholds.alpha);
+        let p_value = mann_whitney(baseline, candidate);
+
+        let significant = match thresholds.test {
+            Test::MannWhitney => p_value < thresholds.alpha,
+            Test::Bootstrap => ratio_ci.low > 1.0 || ratio_ci.high < 1.0,
+        };
+
+        let verdict = if !significant || (ratio - 1.0).abs() < thresholds.min_change {
+            Verdict::NoChange
+        } else {
+            match (metric.is_time(), ratio < 1.0) {
+                (true, true) => Verdict::Faster,
+                (true, false) => Verdict::Slower,
+                (false, true) => Verdict::Lower,
+                (false, false) => Verdict::Higher,
+            }
+        };
+
+        Some(Self {
+            baseline: base_median,
+            candidate: candidate_median,
+            ratio,
+            ratio_ci,
+            p_value,
+            verdict,
+        })
+    }
+
+    /// The relative change of the candidate, such as `-0.1` for 10% less
+    pub fn change(&self) -> f64 {
+        self.ratio - 1.0
+    }
+}
+
+/// Compares every metric of every sample and build variant that two results
+/// have in common
+pub fn compare_results(
+    baseline: &BenchResult,
+    candidate: &BenchResult,
+    thresholds: &Thresholds,
+) -> Vec<SampleComparison> {
+    let mut comparisons = Vec::new();
+    for base in baseline.variants() {
+        let Some(other) = candidate.variant(base.variant) else {
+            continue;
+        };
+
+        for (sample, metric, values) in base.series() {
+            let Some((_, _, others)) = other
+                .series()
+                .find(|(name, other, _)| *name == sample && *other == metric)
+            else {
+                continue;
+            };
+
+            if let Some(comparison) = Comparison::of(values, others, metric, thresholds) {
+                comparisons.push(SampleComparison {
+                    variant: base.variant,
+                    sample: sample.to_owned(),
+                    metric,
+                    comparison,
+                });
+            }
+        }
+    }
+
+    comparisons
+}
+
+impl FromStr for Test {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mann-whitney" => Ok(Self::MannWhitney),
+            "bootstrap" => Ok(Self::Bootstrap),
+            _ => Err(format!("unknown test `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Test {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MannWhitney => write!(f, "mann-whitney"),
+            Self::Bootstrap => write!(f, "bootstrap"),
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Faster => write!(f, "faster"),
+            Self::Slower => write!(f, "slower"),
+            Self::Lower => write!(f, "lower"),
+            Self::Higher => write!(f, "higher"),
+            Self::NoChange => write!(f, "no detectable change"),
+        }
+    }
+}
+
+fn median(samples: &[f64]) -> f64 {
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    percentile(&sorted, 0.5)
+}
+
+/// Returns the bootstrap confidence interval of the ratio of the medians,
+/// resampling both runs independently
+fn ratio_interval(baseline: &[f64], candidate: &[f64], confidence: f64) -> Interval {
+    let mut rng = bootstrap_rng();
+    let mut base_buffer = vec![0.0; baseline.len()];
+    let mut candidate_buffer = vec![0.0; candidate.len()];
+    let mut ratios = Vec::with_capacity(BOOTSTRAP_RESAMPLES);
+
+    for _ in 0..BOOTSTRAP_RESAMPLES {
+        resample(baseline, &mut rng, &mut base_buffer);
+        resample(candidate, &mut rng, &mut candidate_buffer);
+        ratios.push(resample_median(&mut candidate_buffer) / resample_median(&mut base_buffer));
+    }
+
+    interval(&mut ratios, confidence)
+}
+
+/// Returns the p-value of the two-sided Mann–Whitney U test, using the
+/// normal approximation with tie and continuity corrections
+fn mann_whitney(baseline: &[f64], candidate: &[f64]) -> f64 {
+    let (n1, n2) = (baseline.len() as f64, candidate.len() as f64);
+    let mut combined = baseline
+        .iter()
+        .map(|&x| (x, true))
+        .chain(candidate.iter().map(|&x| (x, false)))
+        .collect::<Vec<_>>();
+    combined.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // Tied values share the average of their ranks.
+    let mut rank_sum = 0.0;
+    let mut ties = 0.0;
+    let mut start = 0;
+    while start < combined.len() {
+        let mut end = start;
+        while end + 1 < combined.len() && combined[end + 1].0 == combined[start].0 {
+            end += 1;
+        }
+
+        let count = (end - start + 1) as f64;
+        let rank = (start + end) as f64 / 2.0 + 1.0;
+        let in_baseline = combined[start..=end].iter().filter(|(_, b)| *b).count();
+        rank_sum += rank * in_baseline as f64;
+        ties += count.powi(3) - count;
+        start = end + 1;
+    }
+
+    let n = n1 + n2;
+    let u = rank_sum - n1 * (n1 + 1.0) / 2.0;
+    let mean = n1 * n2 / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if variance <= 0.0 {
+        return 1.0;
+    }
+
+    let z = ((u - mean).abs() - 0.5).max(0.0) / variance.sqrt();
+    erfc(z / std::f64::consts::SQRT_2).min(1.0)
+}
+
+/// The complementary error function for non-negative arguments, accurate to
+/// about 2e-7 (Abramowitz and Stegun, 7.1.26)
+fn erfc(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let poly = t
+        * (0.254_829_592
+            + t * (-0.284_496_736
+                + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    poly * (-x * x).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() < tolerance,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn erfc_matches_reference_values() {
+        assert_close(erfc(0.0), 1.0, 2e-7);
+        assert_close(erfc(0.5), 0.479_500_122, 2e-7);
+        assert_close(erfc(1.0), 0.157_299_207, 2e-7);
+        assert_close(erfc(2.0), 0.004_677_735, 2e-7);
+    }
+
+    #[test]
+    fn mann_whitney_matches_reference_values() {
+        // The asymptotic p-values with continuity correction, as given by
+        // `scipy.stats.mannwhitneyu(x, y, method="asymptotic")`.
+        assert_close(
+            mann_whitney(&[1.0, 2.0, 3.0], &[4.0, 5.0, 6.0]),
+            0.080_856,
+            1e-6,
+        );
+        assert_close(
+            mann_whitney(&[4.0, 5.0, 6.0], &[1.0, 2.0, 3.0]),
+            0.080_856,
+            1e-6,
+        );
+
+        let low = (1..=10).map(f64::from).collect::<Vec<_>>();
+        let high = (11..=20).map(f64::from).collect::<Vec<_>>();
+        assert_close(mann_whitney(&low, &high), 0.000_182_672, 1e-6);
+
+        // U = 7 with two groups of three ties.
+        let baseline = [1.0, 2.0, 2.0, 3.0, 5.0];
+        let candidate = [2.0, 3.0, 3.0, 4.0, 6.0];
+        assert_close(mann_whitney(&baseline, &candidate), 0.284_284, 1e-6);
+    }
+
+    #[test]
+    fn mann_whitney_of_degenerate_samples() {
+        assert_eq!(mann_whitney(&[5.0, 5.0, 5.0], &[5.0, 5.0, 5.0]), 1.0);
+        assert_eq!(mann_whitney(&[1.0], &[1.0]), 1.0);
+        assert_close(mann_whitney(&[1.0], &[2.0]), 1.0, 2e-7);
+    }
+
+    #[test]
+    fn verdicts_depend_on_the_metric() {
+        let baseline = (1..=20).map(|x| 100.0 + f64::from(x)).collect::<Vec<_>>();
+        let candidate = baseline.iter().map(|x| x * 2.0).collect::<Vec<_>>();
+        let thresholds = Thresholds::default();
+
+        let verdict = |baseline: &[f64], candidate: &[f64], metric| {
+            Comparison::of(baseline, candidate, metric, &thresholds)
+                .unwrap()
+                .verdict
+        };
+
+        let walltime = SampleMetric::Walltime;
+        let memory = SampleMetric::ResidentMemory;
+        assert_eq!(verdict(&baseline, &candidate, walltime), Verdict::Slower);
+        assert_eq!(verdict(&candidate, &baseline, walltime), Verdict::Faster);
+        assert_eq!(verdict(&baseline, &candidate, memory), Verdict::Higher);
+        assert_eq!(verdict(&candidate, &baseline, memory), Verdict::Lower);
+        assert_eq!(verdict(&baseline, &baseline, walltime), Verdict::NoChange);
+    }
+
+    #[test]
+    fn thresholds_validation() {
+        let valid = Thresholds::default();
+        assert_eq!(valid.validate(), Ok(()));
+
+        for alpha in [0.0, 1.0, -0.5, f64::NAN] {
+            assert!(Thresholds { alpha, ..valid }.validate().is_err());
+        }
+
+        for min_change in [-0.01, f64::NAN] {
+            assert!(Thresholds {
+                min_change,
+                ..valid
+            }
+            .validate()
+            .is_err());
+        }
+
+        assert_eq!(
+            Thresholds {
+                min_change: 0.0,
+                ..valid
+            }
+            .validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn comparison_needs_samples_and_a_baseline() {
+        let thresholds = Thresholds::default();
+        let metric = SampleMetric::Walltime;
+        assert_eq!(Comparison::of(&[], &[1.0], metric, &thresholds), None);
+        assert_eq!(Comparison::of(&[1.0], &[], metric, &thresholds), None);
+        assert_eq!(Comparison::of(&[0.0], &[1.0], metric, &thresholds), None);
+    }
+}
@@ -0,0 +1,811 @@
+//! The versioned envelope every message is sent in.
+//!
+//! Messages are encoded with one of several [`Encoding`]s, selected by the
+//! content type of the AMQP message. In bincode, an envelope starts with a
+//! fixed header that never changes, followed by the encoded message:
+//!
+//! | Field   | Size | Content                                   |
+//! |---------|------|-------------------------------------------|
+//! | magic   | 4    | `TYPS`                                    |
+//! | major   | 2    | The major schema version, little endian   |
+//! | minor   | 2    | The minor schema version, little endian   |
+//! | kind    | 1    | The [`MessageKind`] of the payload        |
+//! | length  | 4    | The length of the payload, little endian  |
+//! | payload | n    | The message                               |
+//!
+//! In JSON and CBOR, an envelope is a map with the same information, which
+//! can be produced and consumed without a Rust library:
+//!
+//! ```json
+//! { "major": 2, "minor": 0, "kind": "query", "message": { "id": "..." } }
+//! ```
+//!
+//! The schema versions follow these compatibility rules:
+//!
+//! - A minor version may only append fields with a default to the end of a
+//!   top-level message, such as [`BenchResult`](crate::BenchResult). Readers
+//!   decode the fields they know and ignore the unknown trailing bytes of the
+//!   payload, so old readers keep working with new writers. Fields missing
+//!   at the end of the payload take their default, so new readers keep
+//!   working with old writers. JSON and CBOR readers ignore unknown fields
+//!   anywhere.
+//! - Any other change, including adding a field to a nested type such as
+//!   [`BenchSamples`](crate::BenchSamples), reordering or removing fields, or
+//!   adding enum variants, bumps the major version.
+//! - Readers reject messages of another major version with
+//!   [`DecodeError::Version`] instead of decoding garbage.
+//!
+//! | Version | Change                                              |
+//! |---------|-----------------------------------------------------|
+//! | 1.0     | Initial schema                                      |
+//! | 1.1     | Samples, tags, profile, variants, baseline and      |
+//! |         | priority in [`BenchQuery`](crate::BenchQuery)       |
+//! | 1.2     | [`BenchProgress`](crate::BenchProgress) messages    |
+//! | 1.3     | Build variants in                                   |
+//! |         | [`BenchResult`](crate::BenchResult)                 |
+//! | 2.0     | Typed failure stage and partial samples in          |
+//! |         | [`BenchResult::Failure`](crate::BenchResult)        |
+//! | 2.1     | [`Provenance`](crate::Provenance) of results        |
+//! | 2.2     | [`Summary`](crate::Summary) statistics of results   |
+//! | 2.3     | Branch of queries, and                              |
+//! |         | [`RegressionAlert`](crate::RegressionAlert)         |
+//! |         | messages                                            |
+
+use std::{cell::Cell, fmt, io};
+
+use bincode::Options;
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, EnumAccess, SeqAccess, VariantAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+
+use crate::{BenchProgress, BenchQuery, BenchResult, RegressionAlert};
+
+/// The major version of the message schema
+pub const SCHEMA_MAJOR: u16 = 2;
+
+/// The minor version of the message schema
+pub const SCHEMA_MINOR: u16 = 3;
+
+const MAGIC: &[u8; 4] = b"TYPS";
+const HEADER_LEN: usize = 13;
+
+/// The type of the message in an envelope.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum MessageKind {
+    Query = 1,
+    Result = 2,
+    Progress = 3,
+    Alert = 4,
+}
+
+/// How the envelope and its message are serialized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// The compact binary encoding used between Rust services
+    #[default]
+    Bincode,
+
+    /// JSON, for consumers without a bincode implementation
+    Json,
+
+    /// CBOR, a compact binary alternative to JSON
+    Cbor,
+}
+
+/// A message that can be sent in an envelope.
+pub trait Message: Serialize + DeserializeOwned {
+    /// The type tag of the message
+    const KIND: MessageKind;
+}
+
+impl Message for BenchQuery {
+    const KIND: MessageKind = MessageKind::Query;
+}
+
+impl Message for BenchResult {
+    const KIND: MessageKind = MessageKind::Result;
+}
+
+impl Message for BenchProgress {
+    const KIND: MessageKind = MessageKind::Progress;
+}
+
+impl Message for RegressionAlert {
+    const KIND: MessageKind = MessageKind::Alert;
+}
+
+/// The header of an envelope.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub major: u16,
+    pub minor: u16,
+    pub kind: MessageKind,
+    pub length: u32,
+}
+
+/// Why an envelope could not be decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The data is not an envelope, or is cut off
+    Malformed(String),
+
+    /// The message was written with an incompatible schema version
+    Version { major: u16, minor: u16 },
+
+    /// The envelope holds another type of message than expected
+    Kind {
+        expected: MessageKind,
+        found: MessageKind,
+    },
+
+    /// The content type does not name a supported encoding
+    ContentType(String),
+
+    /// The payload does not match the schema of the message
+    Payload(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Why a message could not be encoded.
+#[derive(Debug)]
+pub struct EncodeError(Box<dyn std::error::Error + Send + Sync>);
+
+/// The self-describing form of an envelope, used by JSON and CBOR.
+#[derive(Serialize, Deserialize)]
+struct Tagged<M> {
+    major: u16,
+    minor: u16,
+    kind: MessageKind,
+    message: M,
+}
+
+/// The header of a self-describing envelope, decoded before its message
+/// such that version mismatches are reported as such.
+#[derive(Deserialize)]
+struct TaggedHeader {
+    major: u16,
+    minor: u16,
+    kind: MessageKind,
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Query => write!(f, "query"),
+            Self::Result => write!(f, "result"),
+            Self::Progress => write!(f, "progress"),
+            Self::Alert => write!(f, "alert"),
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(reason) => write!(f, "malformed envelope: {}", reason),
+            Self::Version { major, minor } => write!(
+                f,
+                "message uses schema version {}.{}, which this reader of version {}.{} cannot decode",
+                major, minor, SCHEMA_MAJOR, SCHEMA_MINOR
+            ),
+            Self::Kind { expected, found } => {
+                write!(
+                    f,
+                    "expected a {} message, found a {} message",
+                    expected, found
+                )
+            }
+            Self::ContentType(content_type) => {
+                write!(f, "unsupported content type `{}`", content_type)
+            }
+            Self::Payload(err) => write!(f, "invalid message payload: {}", err),
+        }
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to encode message: {}", self.0)
+    }
+}
+
+impl std::error::Error for EncodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Payload(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = DecodeError;
+
+    fn try_from(tag: u8) -> Result<Self, Self::Error> {
+        match tag {
+            1 => Ok(Self::Query),
+            2 => Ok(Self::Result),
+            3 => Ok(Self::Progress),
+            4 => Ok(Self::Alert),
+            _ => Err(DecodeError::Malformed(format!(
+                "unknown message kind {}",
+                tag
+            ))),
+        }
+    }
+}
+
+impl Encoding {
+    /// Selects the encoding named by an AMQP content type, defaulting to
+    /// bincode when there is none
+    pub fn from_content_type(content_type: Option<&str>) -> Result<Self, DecodeError> {
+        let Some(content_type) = content_type else {
+            return Ok(Self::Bincode);
+        };
+
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "" | "application/x-bincode" | "application/octet-stream" => Ok(Self::Bincode),
+            "application/json" => Ok(Self::Json),
+            "application/cbor" => Ok(Self::Cbor),
+            _ => Err(DecodeError::ContentType(content_type.to_owned())),
+        }
+    }
+
+    /// Returns the content type of the encoding
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Bincode => "application/x-bincode",
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// Encodes a message in an envelope of the current schema version
+    pub fn encode<M: Message>(self, message: &M) -> Result<Vec<u8>, EncodeError> {
+        let tagged = Tagged {
+            major: SCHEMA_MAJOR,
+            minor: SCHEMA_MINOR,
+            kind: M::KIND,
+            message,
+        };
+
+        match self {
+            Self::Bincode => encode_bincode(message),
+            Self::Json => serde_json::to_vec(&tagged).map_err(|err| EncodeError(err.into())),
+            Self::Cbor => {
+                let mut data = Vec::new();
+                ciborium::ser::into_writer(&tagged, &mut data)
+                    .map_err(|err| EncodeError(err.into()))?;
+                Ok(data)
+            }
+        }
+    }
+
+    /// Decodes a message from an envelope, checking its version and type
+    pub fn decode<M: Message>(self, data: &[u8]) -> Result<M, DecodeError> {
+        let payload = |err: Box<dyn std::error::Error + Send + Sync>| DecodeError::Payload(err);
+        match self {
+            Self::Bincode => decode_bincode(data),
+            Self::Json => {
+                let header: TaggedHeader = serde_json::from_slice(data)
+                    .map_err(|err| DecodeError::Malformed(err.to_string()))?;
+                check::<M>(header.major, header.minor, header.kind)?;
+
+                let tagged: Tagged<M> =
+                    serde_json::from_slice(data).map_err(|err| payload(err.into()))?;
+                Ok(tagged.message)
+            }
+            Self::Cbor => {
+                let header: TaggedHeader = ciborium::de::from_reader(data)
+                    .map_err(|err| DecodeError::Malformed(err.to_string()))?;
+                check::<M>(header.major, header.minor, header.kind)?;
+
+                let tagged: Tagged<M> =
+                    ciborium::de::from_reader(data).map_err(|err| payload(err.into()))?;
+                Ok(tagged.message)
+            }
+        }
+    }
+}
+
+/// Encodes a message in a bincode envelope of the current schema version
+pub fn encode<M: Message>(message: &M) -> Result<Vec<u8>, EncodeError> {
+    Encoding::Bincode.encode(message)
+}
+
+/// Decodes a message from a bincode envelope, checking its version and type
+pub fn decode<M: Message>(data: &[u8]) -> Result<M, DecodeError> {
+    Encoding::Bincode.decode(data)
+}
+
+fn encode_bincode<M: Message>(message: &M) -> Result<Vec<u8>, EncodeError> {
+    let payload = bincode::serialize(message).map_err(|err| EncodeError(err.into()))?;
+    let length =
+        u32::try_from(payload.len()).map_err(|_| EncodeError("message is too large".into()))?;
+
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&SCHEMA_MAJOR.to_le_bytes());
+    data.extend_from_slice(&SCHEMA_MINOR.to_le_bytes());
+    data.push(M::KIND as u8);
+    data.extend_from_slice(&length.to_le_bytes());
+    data.extend_from_slice(&payload);
+
+    Ok(data)
+}
+
+/// Reads the header of an envelope without decoding its payload
+pub fn peek(data: &[u8]) -> Result<Header, DecodeError> {
+    if data.len() < HEADER_LEN {
+        return Err(DecodeError::Malformed(format!(
+            "expected at least {} bytes, found {}",
+            HEADER_LEN,
+            data.len()
+        )));
+    }
+
+    if &data[..4] != MAGIC {
+        return Err(DecodeError::Malformed("missing magic bytes".into()));
+    }
+
+    let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    Ok(Header {
+        major: u16_at(4),
+        minor: u16_at(6),
+        kind: MessageKind::try_from(data[8])?,
+        length: u32::from_le_bytes([data[9], data[10], data[11], data[12]]),
+    })
+}
+
+fn decode_bincode<M: Message>(data: &[u8]) -> Result<M, DecodeError> {
+    let header = peek(data)?;
+    check::<M>(header.major, header.minor, header.kind)?;
+
+    let payload = data
+        .get(HEADER_LEN..HEADER_LEN + header.length as usize)
+        .ok_or_else(|| DecodeError::Malformed("payload is cut off".into()))?;
+
+    // The limit keeps corrupted lengths inside the payload from causing
+    // huge allocations, trailing bytes are fields of newer minor versions.
+    let remaining = Cell::new(payload.len());
+    let reader = Counted {
+        data: payload,
+        remaining: &remaining,
+    };
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(payload.len() as u64);
+    let mut deserializer = bincode::Deserializer::with_reader(reader, options);
+
+    M::deserialize(Trailing {
+        inner: &mut deserializer,
+        remaining: &remaining,
+    })
+    .map_err(|err| DecodeError::Payload(err))
+}
+
+/// Checks that an envelope holds a message of the expected type in a
+/// compatible schema version
+fn check<M: Message>(major: u16, minor: u16, kind: MessageKind) -> Result<(), DecodeError> {
+    if major != SCHEMA_MAJOR {
+        return Err(DecodeError::Version { major, minor });
+    }
+
+    if kind != M::KIND {
+        return Err(DecodeError::Kind {
+            expected: M::KIND,
+            found: kind,
+        });
+    }
+
+    Ok(())
+}
+
+/// Reads a bincode payload, keeping track of how many bytes are left.
+struct Counted<'a> {
+    data: &'a [u8],
+    remaining: &'a Cell<usize>,
+}
+
+impl io::Read for Counted<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.data.read(buf)?;
+        self.remaining.set(self.data.len());
+        Ok(read)
+    }
+}
+
+/// Ends the fields of a top-level message where its bincode payload ends,
+/// such that the fields that older minor versions lack take their default.
+///
+/// It wraps the deserializer, the visitor and the accessors of the message
+/// in turn, while the fields of nested types are decoded as usual.
+struct Trailing<'a, T> {
+    inner: T,
+    remaining: &'a Cell<usize>,
+}
+
+impl<'a, T> Trailing<'a, T> {
+    fn wrap<U>(&self, inner: U) -> Trailing<'a, U> {
+        Trailing {
+            inner,
+            remaining: self.remaining,
+        }
+    }
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for Trailing<'_, D> {
+    type Error = D::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("messages must be structs or enums"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let visitor = self.wrap(visitor);
+        self.inner.deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let visitor = self.wrap(visitor);
+        self.inner.deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map identifier ignored_any
+    }
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for Trailing<'_, V> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.expecting(f)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        let seq = self.wrap(seq);
+        self.inner.visit_seq(seq)
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        let data = self.wrap(data);
+        self.inner.visit_enum(data)
+    }
+}
+
+impl<'de, A: SeqAccess<'de>> SeqAccess<'de> for Trailing<'_, A> {
+    type Error = A::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        if self.remaining.get() == 0 {
+            return Ok(None);
+        }
+
+        self.inner.next_element_seed(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, 'de, A: EnumAccess<'de>> EnumAccess<'de> for Trailing<'a, A> {
+    type Error = A::Error;
+    type Variant = Trailing<'a, A::Variant>;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<(T::Value, Self::Variant), Self::Error> {
+        let remaining = self.remaining;
+        let (value, variant) = self.inner.variant_seed(seed)?;
+        Ok((
+            value,
+            Trailing {
+                inner: variant,
+                remaining,
+            },
+        ))
+    }
+}
+
+impl<'de, A: VariantAccess<'de>> VariantAccess<'de> for Trailing<'_, A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        self.inner.unit_variant()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, Self::Error> {
+        self.inner.newtype_variant_seed(seed)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.inner.tuple_variant(len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let visitor = self.wrap(visitor);
+        self.inner.struct_variant(fields, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BuildVariant, SampleMetric, Stage};
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Bincode, Encoding::Json, Encoding::Cbor];
+
+    fn query() -> BenchQuery {
+        let mut query = BenchQuery::new(
+            "abc".into(),
+            "https://example.com/repo".into(),
+            "1234".into(),
+        );
+        query.samples = vec!["a.typ".into()];
+        query.tags = vec!["math".into()];
+        query.profile = Some("main".into());
+        query.variants = vec![BuildVariant::Pgo];
+        query.baseline = Some("5678".into());
+        query.priority = 3;
+        query.branch = Some("main".into());
+        query
+    }
+
+    fn alert() -> RegressionAlert {
+        RegressionAlert {
+            branch: "main".into(),
+            variant: BuildVariant::Plain,
+            sample: "a.typ".into(),
+            metric: SampleMetric::Walltime,
+            commit: "1234".into(),
+            id: "abc".into(),
+            before: 1.0,
+            after: 1.5,
+            change: 0.5,
+            confidence: 0.99,
+        }
+    }
+
+    /// Compares messages by their JSON form, as not every message implements
+    /// `PartialEq`
+    fn assert_same<M: Message>(actual: &M, expected: &M) {
+        assert_eq!(
+            serde_json::to_value(actual).unwrap(),
+            serde_json::to_value(expected).unwrap()
+        );
+    }
+
+    /// Encodes the payload of a bincode envelope with a given version
+    fn bincode_envelope(major: u16, minor: u16, kind: MessageKind, payload: &[u8]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&major.to_le_bytes());
+        data.extend_from_slice(&minor.to_le_bytes());
+        data.push(kind as u8);
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn round_trip_in_every_encoding() {
+        for encoding in ENCODINGS {
+            let data = encoding.encode(&query()).unwrap();
+            assert_same(&encoding.decode::<BenchQuery>(&data).unwrap(), &query());
+
+            let data = encoding.encode(&alert()).unwrap();
+            assert_eq!(encoding.decode::<RegressionAlert>(&data).unwrap(), alert());
+        }
+    }
+
+    #[test]
+    fn bincode_header() {
+        let data = encode(&query()).unwrap();
+        let header = peek(&data).unwrap();
+        assert_eq!(header.major, SCHEMA_MAJOR);
+        assert_eq!(header.minor, SCHEMA_MINOR);
+        assert_eq!(header.kind, MessageKind::Query);
+        assert_eq!(header.length as usize, data.len() - HEADER_LEN);
+    }
+
+    #[test]
+    fn decode_rejects_another_kind() {
+        for encoding in ENCODINGS {
+            let data = encoding.encode(&query()).unwrap();
+            assert!(matches!(
+                encoding.decode::<RegressionAlert>(&data),
+                Err(DecodeError::Kind {
+                    expected: MessageKind::Alert,
+                    found: MessageKind::Query,
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn decode_rejects_another_major_version() {
+        let payload = bincode::serialize(&query()).unwrap();
+        let data = bincode_envelope(SCHEMA_MAJOR + 1, 0, MessageKind::Query, &payload);
+        assert!(matches!(
+            decode::<BenchQuery>(&data),
+            Err(DecodeError::Version { major, minor: 0 }) if major == SCHEMA_MAJOR + 1
+        ));
+
+        let json = serde_json::json!({
+            "major": SCHEMA_MAJOR - 1,
+            "minor": 0,
+            "kind": "query",
+            "message": query(),
+        });
+        assert!(matches!(
+            Encoding::Json.decode::<BenchQuery>(&serde_json::to_vec(&json).unwrap()),
+            Err(DecodeError::Version { .. })
+        ));
+    }
+
+    #[test]
+    fn decode_older_minor_version() {
+        // A query of version 2.2, which had no branch yet.
+        let mut old = serde_json::to_value(query()).unwrap();
+        old.as_object_mut().unwrap().remove("branch");
+
+        let json = serde_json::json!({
+            "major": SCHEMA_MAJOR,
+            "minor": 2,
+            "kind": "query",
+            "message": old,
+        });
+        let decoded: BenchQuery = Encoding::Json
+            .decode(&serde_json::to_vec(&json).unwrap())
+            .unwrap();
+        assert_eq!(decoded.branch, None);
+        assert_eq!(decoded.priority, 3);
+
+        let mut cbor = Vec::new();
+        ciborium::ser::into_writer(&json, &mut cbor).unwrap();
+        let decoded: BenchQuery = Encoding::Cbor.decode(&cbor).unwrap();
+        assert_eq!(decoded.branch, None);
+
+        let payload = bincode::serialize(&query()).unwrap();
+        let without_branch =
+            &payload[..payload.len() - bincode::serialize(&query().branch).unwrap().len()];
+        let data = bincode_envelope(SCHEMA_MAJOR, 2, MessageKind::Query, without_branch);
+        let decoded = decode::<BenchQuery>(&data).unwrap();
+        assert_eq!(decoded.branch, None);
+        assert_eq!(decoded.priority, 3);
+
+        // Only fields with a default may be missing.
+        let data = bincode_envelope(SCHEMA_MAJOR, 2, MessageKind::Query, &payload[..8]);
+        assert!(matches!(
+            decode::<BenchQuery>(&data),
+            Err(DecodeError::Payload(_))
+        ));
+    }
+
+    #[test]
+    fn decode_older_minor_version_of_a_variant() {
+        // A failure of version 2.1, which had no summaries yet.
+        let failure = BenchResult::Failure {
+            id: "abc".into(),
+            stage: Stage::Build,
+            variant: None,
+            clone: None,
+            fetch: None,
+            build: None,
+            bench_e2e: None,
+            bench_walltime: None,
+            samples: Vec::new(),
+            walltimes: Vec::new(),
+            variants: Vec::new(),
+            provenance: Default::default(),
+            summaries: Vec::new(),
+        };
+        let payload = bincode::serialize(&failure).unwrap();
+        let without_summaries = &payload[..payload.len() - 8];
+        let data = bincode_envelope(SCHEMA_MAJOR, 1, MessageKind::Result, without_summaries);
+        assert_same(&decode::<BenchResult>(&data).unwrap(), &failure);
+    }
+
+    #[test]
+    fn decode_newer_minor_version() {
+        let mut payload = bincode::serialize(&query()).unwrap();
+        payload.extend_from_slice(&[1, 2, 3]);
+        let data = bincode_envelope(SCHEMA_MAJOR, SCHEMA_MINOR + 1, MessageKind::Query, &payload);
+        assert_same(&decode::<BenchQuery>(&data).unwrap(), &query());
+
+        let mut json = serde_json::to_value(query()).unwrap();
+        json.as_object_mut()
+            .unwrap()
+            .insert("unknown".into(), 1.into());
+        let json = serde_json::json!({
+            "major": SCHEMA_MAJOR,
+            "minor": SCHEMA_MINOR + 1,
+            "kind": "query",
+            "message": json,
+        });
+        let decoded: BenchQuery = Encoding::Json
+            .decode(&serde_json::to_vec(&json).unwrap())
+            .unwrap();
+        assert_same(&decoded, &query());
+    }
+
+    #[test]
+    fn decode_rejects_malformed_envelopes() {
+        let data = encode(&query()).unwrap();
+        assert!(matches!(
+            decode::<BenchQuery>(&data[..5]),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert!(matches!(
+            decode::<BenchQuery>(&data[..data.len() - 1]),
+            Err(DecodeError::Malformed(_))
+        ));
+
+        let mut wrong_magic = data.clone();
+        wrong_magic[0] = b'X';
+        assert!(matches!(
+            decode::<BenchQuery>(&wrong_magic),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn content_types() {
+        for encoding in ENCODINGS {
+            assert_eq!(
+                Encoding::from_content_type(Some(encoding.content_type())).unwrap(),
+                encoding
+            );
+        }
+
+        assert_eq!(
+            Encoding::from_content_type(None).unwrap(),
+            Encoding::Bincode
+        );
+        assert_eq!(
+            Encoding::from_content_type(Some("application/JSON; charset=utf-8")).unwrap(),
+            Encoding::Json
+        );
+        assert!(matches!(
+            Encoding::from_content_type(Some("text/plain")),
+            Err(DecodeError::ContentType(_))
+        ));
+    }
+}
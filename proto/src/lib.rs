@@ -1,12 +1,161 @@
+use std::{collections::BTreeMap, fmt, str::FromStr, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
 pub use bincode::{deserialize_from, serialize};
+pub use comparison::{compare_results, Comparison, SampleComparison, Thresholds, Verdict};
+pub use envelope::{
+    decode, encode, peek, DecodeError, EncodeError, Encoding, Header, Message, MessageKind,
+};
+pub use statistics::{SampleMetric, SampleSummary, Summary};
+
+pub mod changepoint;
+pub mod comparison;
+pub mod envelope;
+pub mod statistics;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BenchQuery {
     pub id: String,
     pub repo: String,
     pub commit: String,
+
+    /// The sample files to benchmark, relative to the samples directory, in
+    /// addition to the samples of `tags`. The runner's samples are used when
+    /// both are empty.
+    #[serde(default)]
+    pub samples: Vec<String>,
+
+    /// The tags of the samples to benchmark, as defined by the runner
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// The name of the benchmark settings, such as `main` or `other`
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// The build variants to benchmark, every variant of the pipeline if
+    /// empty
+    #[serde(default)]
+    pub variants: Vec<BuildVariant>,
+
+    /// A commit to benchmark with the same options, to compare against
+    #[serde(default)]
+    pub baseline: Option<String>,
+
+    /// The priority of the query, higher priorities are served first
+    #[serde(default)]
+    pub priority: u8,
+
+    /// The branch the commit belongs to, whose history the result is added
+    /// to and checked for regressions
+    #[serde(default)]
+    pub branch: Option<String>,
+}
+
+/// The maximum length of the ID of a query
+pub const MAX_QUERY_ID_LEN: usize = 64;
+
+impl BenchQuery {
+    /// Whether an ID may name a query, which it must to be accepted, as IDs
+    /// end up in file and container names: 1 to 64 ASCII letters, digits,
+    /// `-` and `_`
+    pub fn is_valid_id(id: &str) -> bool {
+        (1..=MAX_QUERY_ID_LEN).contains(&id.len())
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+
+    /// Creates a query with the runner's default options
+    pub fn new(id: String, repo: String, commit: String) -> Self {
+        Self {
+            id,
+            repo,
+            commit,
+            samples: Vec::new(),
+            tags: Vec::new(),
+            profile: None,
+            variants: Vec::new(),
+            baseline: None,
+            priority: 0,
+            branch: None,
+        }
+    }
+}
+
+/// How the benchmarked binary is built.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildVariant {
+    /// A plain release build
+    Plain,
+
+    /// A release build optimized with profile-guided optimization
+    Pgo,
+}
+
+impl FromStr for BuildVariant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "pgo" => Ok(Self::Pgo),
+            _ => Err(format!("unknown build variant `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for BuildVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain => write!(f, "plain"),
+            Self::Pgo => write!(f, "pgo"),
+        }
+    }
+}
+
+/// The kind of operation a pipeline stage performs on the sandbox.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Clone,
+    Fetch,
+    Build,
+    BenchE2e,
+    BenchWalltime,
+    PgoBuild,
+    PgoBuildProfile,
+    PgoProfile,
+}
+
+impl Stage {
+    /// Whether the stage builds the binary
+    pub fn is_build(self) -> bool {
+        matches!(self, Self::Build | Self::PgoBuild)
+    }
+
+    /// Whether the stage only produces inputs for building the binary, as
+    /// opposed to producing results
+    pub fn is_build_input(self) -> bool {
+        !matches!(self, Self::BenchE2e | Self::BenchWalltime)
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Clone => write!(f, "clone"),
+            Self::Fetch => write!(f, "fetch"),
+            Self::Build => write!(f, "build"),
+            Self::BenchE2e => write!(f, "bench_e2e"),
+            Self::BenchWalltime => write!(f, "bench_walltime"),
+            Self::PgoBuild => write!(f, "pgo_build"),
+            Self::PgoBuildProfile => write!(f, "pgo_build_profile"),
+            Self::PgoProfile => write!(f, "pgo_profile"),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -14,6 +163,154 @@ pub struct StageOutput {
     pub stdout: Vec<String>,
     pub stderr: Vec<String>,
     pub exitcode: i32,
+    pub outcome: StageOutcome,
+    pub resources: StageResources,
+}
+
+/// The resources used by the container of a stage.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct StageResources {
+    /// How long the container ran for
+    pub wall_time: Duration,
+
+    /// The CPU time spent by all processes of the container
+    pub cpu_time: Duration,
+
+    /// The peak memory usage in bytes, excluding the page cache
+    pub peak_memory: u64,
+
+    /// The number of bytes read from block devices
+    pub block_read: u64,
+
+    /// The number of bytes written to block devices
+    pub block_write: u64,
+
+    /// The number of bytes received over the network
+    pub net_rx: u64,
+
+    /// The number of bytes sent over the network
+    pub net_tx: u64,
+}
+
+/// How the container of a stage ended.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum StageOutcome {
+    /// The container exited with a zero exit code
+    #[default]
+    Success,
+
+    /// The container exited with a non-zero exit code
+    ExitCode(i64),
+
+    /// The command in the container exceeded the stage's soft timeout and
+    /// was stopped by `timeout`
+    SoftTimeout,
+
+    /// The container exceeded the stage's hard timeout and was killed by
+    /// the runner
+    HardTimeout,
+
+    /// The container exceeded its memory limit and was killed
+    OomKilled,
+
+    /// The runner failed to run the container or to observe its result
+    InfraError(String),
+}
+
+impl StageOutcome {
+    /// Whether the container exited with a zero exit code
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Success)
+    }
+}
+
+impl StageResources {
+    /// Accounts for the resources of another container of the same stage,
+    /// which ran after this one
+    pub fn add(&mut self, other: &Self) {
+        self.wall_time += other.wall_time;
+        self.cpu_time += other.cpu_time;
+        self.peak_memory = self.peak_memory.max(other.peak_memory);
+        self.block_read += other.block_read;
+        self.block_write += other.block_write;
+        self.net_rx += other.net_rx;
+        self.net_tx += other.net_tx;
+    }
+}
+
+impl fmt::Display for StageOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Success => write!(f, "success"),
+            Self::ExitCode(code) => write!(f, "exited with code {}", code),
+            Self::SoftTimeout => write!(f, "soft timeout"),
+            Self::HardTimeout => write!(f, "hard timeout"),
+            Self::OomKilled => write!(f, "killed for running out of memory"),
+            Self::InfraError(err) => write!(f, "infrastructure error: {}", err),
+        }
+    }
+}
+
+/// A regression found in the history of a branch.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RegressionAlert {
+    pub branch: String,
+    pub variant: BuildVariant,
+    pub sample: String,
+    pub metric: SampleMetric,
+
+    /// The first commit of the regression
+    pub commit: String,
+
+    /// The ID of the query that benchmarked the commit
+    pub id: String,
+
+    /// The mean of the medians of the commits before the regression
+    pub before: f64,
+
+    /// The mean of the medians of the commits from the regression on
+    pub after: f64,
+
+    /// The relative change, such as `0.02` for 2% slower
+    pub change: f64,
+
+    /// The confidence that the regression is real
+    pub confidence: f64,
+}
+
+/// An update on a running query, published while its pipeline runs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BenchProgress {
+    /// The ID of the query, or of its baseline
+    pub id: String,
+
+    /// What happened
+    pub event: ProgressEvent,
+}
+
+/// A step in the pipeline of a query.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A stage started
+    StageStarted { stage: String },
+
+    /// A stage finished, successfully or not
+    StageFinished {
+        stage: String,
+        duration: Duration,
+        outcome: StageOutcome,
+    },
+
+    /// The running benchmark stage finished a sample
+    SampleDone {
+        sample: String,
+        done: u32,
+        total: u32,
+
+        /// The estimated time until the stage finishes every sample
+        eta: Option<Duration>,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -32,8 +329,63 @@ pub struct BenchWalltimeSamples {
     pub walltime: Vec<f64>,
 }
 
+/// Where and how a result was produced, such that results of different
+/// machines or toolchains are not compared by accident.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct Provenance {
+    /// The model name of the host CPU
+    pub cpu_model: String,
+
+    /// The number of cores of the host
+    pub cores: u32,
+
+    /// The kernel version of the host
+    pub kernel: String,
+
+    /// The version of the Docker engine
+    pub docker: String,
+
+    /// The cgroup version and driver of the Docker engine, such as
+    /// `2 (systemd)`
+    pub cgroup: String,
+
+    /// The cpusets the stages were pinned to, by their name in the pipeline
+    pub cpusets: BTreeMap<String, String>,
+
+    /// The digests of the images of the stages, by image name
+    pub images: BTreeMap<String, String>,
+
+    /// The version of rustc that built the binary, if the build reported it
+    pub rustc: Option<String>,
+
+    /// The version of the runner
+    pub runner: String,
+
+    /// The SHA-256 hash of the runner's profile
+    pub profile_hash: String,
+}
+
+/// The measurements of one build variant of a commit.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VariantResult {
+    /// How the benchmarked binary was built
+    pub variant: BuildVariant,
+
+    /// The end-to-end samples of the variant
+    pub samples: Vec<BenchSamples>,
+
+    /// The walltime samples of the variant
+    pub walltimes: Vec<BenchWalltimeSamples>,
+
+    /// The outputs of the stages that built and benchmarked the variant, by
+    /// their name in the pipeline
+    pub stages: BTreeMap<String, StageOutput>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum BenchResult {
+    /// The pipeline succeeded. The samples and the build and benchmark
+    /// outputs outside of `variants` are those of the plain build, if it ran.
     Success {
         id: String,
         samples: Vec<BenchSamples>,
@@ -43,14 +395,81 @@ pub enum BenchResult {
         build: StageOutput,
         bench_e2e: StageOutput,
         bench_walltime: StageOutput,
+
+        /// The measurements of every build variant that ran
+        #[serde(default)]
+        variants: Vec<VariantResult>,
+
+        /// Where and how the result was produced
+        #[serde(default)]
+        provenance: Provenance,
+
+        /// The statistics of every metric of every sample of the variants
+        #[serde(default)]
+        summaries: Vec<SampleSummary>,
     },
+    /// A stage of the pipeline failed. The samples are those that finished
+    /// before the failure, of the plain build outside of `variants`.
     Failure {
         id: String,
-        stage: String,
+        stage: Stage,
+
+        /// The build variant of the failed stage, if it is specific to one
+        variant: Option<BuildVariant>,
         clone: Option<StageOutput>,
         fetch: Option<StageOutput>,
         build: Option<StageOutput>,
         bench_e2e: Option<StageOutput>,
         bench_walltime: Option<StageOutput>,
+        samples: Vec<BenchSamples>,
+        walltimes: Vec<BenchWalltimeSamples>,
+        variants: Vec<VariantResult>,
+
+        /// Where and how the result was produced
+        #[serde(default)]
+        provenance: Provenance,
+
+        /// The statistics of every metric of every sample of the variants
+        #[serde(default)]
+        summaries: Vec<SampleSummary>,
     },
 }
+
+impl BenchResult {
+    /// Returns the statistics of every metric of every sample
+    pub fn summaries(&self) -> &[SampleSummary] {
+        match self {
+            Self::Success { summaries, .. } | Self::Failure { summaries, .. } => summaries,
+        }
+    }
+
+    /// Returns the measurements of every build variant that ran
+    pub fn variants(&self) -> &[VariantResult] {
+        match self {
+            Self::Success { variants, .. } | Self::Failure { variants, .. } => variants,
+        }
+    }
+
+    /// Returns the measurements of a build variant, if it ran
+    pub fn variant(&self, variant: BuildVariant) -> Option<&VariantResult> {
+        self.variants().iter().find(|v| v.variant == variant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_ids() {
+        assert!(BenchQuery::is_valid_id("abc"));
+        assert!(BenchQuery::is_valid_id("Nightly_2024-01-01"));
+        assert!(BenchQuery::is_valid_id(&"a".repeat(MAX_QUERY_ID_LEN)));
+
+        assert!(!BenchQuery::is_valid_id(""));
+        assert!(!BenchQuery::is_valid_id(&"a".repeat(MAX_QUERY_ID_LEN + 1)));
+        assert!(!BenchQuery::is_valid_id("../etc"));
+        assert!(!BenchQuery::is_valid_id("a b"));
+        assert!(!BenchQuery::is_valid_id("ä"));
+    }
+}
@@ -0,0 +1,480 @@
+//! Summary statistics of the samples of a benchmark.
+//!
+//! Percentiles interpolate linearly between the closest ranks. Confidence
+//! intervals are percentile bootstrap intervals, resampled with a fixed seed
+//! such that summarizing the same samples twice gives the same result.
+
+use std::{fmt, str::FromStr};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{BenchSamples, BenchWalltimeSamples, BuildVariant, VariantResult};
+
+/// The number of resamples of the bootstrap
+pub const BOOTSTRAP_RESAMPLES: usize = 1000;
+
+/// The confidence level of the bootstrap intervals
+pub const CONFIDENCE_LEVEL: f64 = 0.95;
+
+/// The seed of the bootstrap resampling
+const BOOTSTRAP_SEED: u64 = 0x7970_7374;
+
+/// The statistics of the samples of one metric.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Summary {
+    /// The number of samples
+    pub count: u64,
+
+    pub mean: f64,
+    pub median: f64,
+    pub min: f64,
+    pub max: f64,
+
+    /// The sample standard deviation
+    pub std_dev: f64,
+
+    /// The median absolute deviation from the median, unscaled
+    pub mad: f64,
+
+    pub p5: f64,
+    pub p95: f64,
+    pub p99: f64,
+
+    /// The bootstrap confidence interval of the mean
+    pub mean_ci: Interval,
+
+    /// The bootstrap confidence interval of the median
+    pub median_ci: Interval,
+
+    /// The samples outside of the Tukey fences
+    pub outliers: Outliers,
+}
+
+/// A confidence interval.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Interval {
+    pub low: f64,
+    pub high: f64,
+}
+
+/// The fences outside of which samples are outliers, at 1.5 and 3 times the
+/// interquartile range beyond the quartiles.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Fences {
+    pub low_severe: f64,
+    pub low_mild: f64,
+    pub high_mild: f64,
+    pub high_severe: f64,
+}
+
+/// How far outside of the fences a sample is.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outlier {
+    LowSevere,
+    LowMild,
+    HighMild,
+    HighSevere,
+}
+
+/// The number of samples of each kind of outlier.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct Outliers {
+    pub low_severe: u64,
+    pub low_mild: u64,
+    pub high_mild: u64,
+    pub high_severe: u64,
+}
+
+/// A measured quantity of the samples of a benchmark.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleMetric {
+    UserTime,
+    SystemTime,
+    VirtualMemory,
+    ResidentMemory,
+    CpuPercent,
+    Walltime,
+}
+
+/// The summary of one metric of one sample of a build variant.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SampleSummary {
+    pub variant: BuildVariant,
+    pub sample: String,
+    pub metric: SampleMetric,
+    pub summary: Summary,
+}
+
+impl Summary {
+    /// Summarizes samples, returning `None` if there are none
+    pub fn of(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+
+        let mean = mean(&sorted);
+        let median = percentile(&sorted, 0.5);
+        let mut deviations = sorted
+            .iter()
+            .map(|x| (x - median).abs())
+            .collect::<Vec<_>>();
+        deviations.sort_by(f64::total_cmp);
+
+        let fences = Fences::of_sorted(&sorted);
+        let mut outliers = Outliers::default();
+        for &sample in &sorted {
+            match fences.classify(sample) {
+                Some(Outlier::LowSevere) => outliers.low_severe += 1,
+                Some(Outlier::LowMild) => outliers.low_mild += 1,
+                Some(Outlier::HighMild) => outliers.high_mild += 1,
+                Some(Outlier::HighSevere) => outliers.high_severe += 1,
+                None => {}
+            }
+        }
+
+        let (mean_ci, median_ci) = bootstrap(&sorted);
+
+        Some(Self {
+            count: sorted.len() as u64,
+            mean,
+            median,
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            std_dev: std_dev(&sorted, mean),
+            mad: percentile(&deviations, 0.5),
+            p5: percentile(&sorted, 0.05),
+            p95: percentile(&sorted, 0.95),
+            p99: percentile(&sorted, 0.99),
+            mean_ci,
+            median_ci,
+            outliers,
+        })
+    }
+}
+
+impl Fences {
+    /// Computes the fences of samples, which must not be empty
+    pub fn of(samples: &[f64]) -> Self {
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        Self::of_sorted(&sorted)
+    }
+
+    fn of_sorted(sorted: &[f64]) -> Self {
+        let q1 = percentile(sorted, 0.25);
+        let q3 = percentile(sorted, 0.75);
+        let iqr = q3 - q1;
+
+        Self {
+            low_severe: q1 - 3.0 * iqr,
+            low_mild: q1 - 1.5 * iqr,
+            high_mild: q3 + 1.5 * iqr,
+            high_severe: q3 + 3.0 * iqr,
+        }
+    }
+
+    /// Returns whether a sample is an outlier, and how far out it is
+    pub fn classify(&self, sample: f64) -> Option<Outlier> {
+        if sample < self.low_severe {
+            Some(Outlier::LowSevere)
+        } else if sample < self.low_mild {
+            Some(Outlier::LowMild)
+        } else if sample > self.high_severe {
+            Some(Outlier::HighSevere)
+        } else if sample > self.high_mild {
+            Some(Outlier::HighMild)
+        } else {
+            None
+        }
+    }
+}
+
+impl Outliers {
+    /// The number of outliers of any kind
+    pub fn total(&self) -> u64 {
+        self.low_severe + self.low_mild + self.high_mild + self.high_severe
+    }
+}
+
+impl SampleMetric {
+    /// Whether the metric is a time, whose changes are faster or slower
+    pub fn is_time(self) -> bool {
+        matches!(self, Self::UserTime | Self::SystemTime | Self::Walltime)
+    }
+}
+
+impl FromStr for SampleMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user_time" => Ok(Self::UserTime),
+            "system_time" => Ok(Self::SystemTime),
+            "virtual_memory" => Ok(Self::VirtualMemory),
+            "resident_memory" => Ok(Self::ResidentMemory),
+            "cpu_percent" => Ok(Self::CpuPercent),
+            "walltime" => Ok(Self::Walltime),
+            _ => Err(format!("unknown metric `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for SampleMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UserTime => write!(f, "user_time"),
+            Self::SystemTime => write!(f, "system_time"),
+            Self::VirtualMemory => write!(f, "virtual_memory"),
+            Self::ResidentMemory => write!(f, "resident_memory"),
+            Self::CpuPercent => write!(f, "cpu_percent"),
+            Self::Walltime => write!(f, "walltime"),
+        }
+    }
+}
+
+impl BenchSamples {
+    /// Returns the samples of every metric
+    pub fn metrics(&self) -> [(SampleMetric, &[f64]); 5] {
+        [
+            (SampleMetric::UserTime, &self.user_time),
+            (SampleMetric::SystemTime, &self.system_time),
+            (SampleMetric::VirtualMemory, &self.virtual_memory),
+            (SampleMetric::ResidentMemory, &self.resident_memory),
+            (SampleMetric::CpuPercent, &self.cpu_percent),
+        ]
+    }
+}
+
+impl BenchWalltimeSamples {
+    /// Returns the samples of every metric
+    pub fn metrics(&self) -> [(SampleMetric, &[f64]); 1] {
+        [(SampleMetric::Walltime, &self.walltime)]
+    }
+}
+
+impl VariantResult {
+    /// Returns the samples of every metric of every sample of the variant,
+    /// by sample name
+    pub fn series(&self) -> impl Iterator<Item = (&str, SampleMetric, &[f64])> {
+        let samples = self.samples.iter().flat_map(|samples| {
+            let name = samples.name.as_str();
+            samples
+                .metrics()
+                .map(|(metric, values)| (name, metric, values))
+        });
+        let walltimes = self.walltimes.iter().flat_map(|samples| {
+            let name = samples.name.as_str();
+            samples
+                .metrics()
+                .map(|(metric, values)| (name, metric, values))
+        });
+
+        samples.chain(walltimes)
+    }
+
+    /// Summarizes every metric of every sample of the variant
+    pub fn summaries(&self) -> Vec<SampleSummary> {
+        self.series()
+            .filter_map(|(sample, metric, values)| {
+                Some(SampleSummary {
+                    variant: self.variant,
+                    sample: sample.to_owned(),
+                    metric,
+                    summary: Summary::of(values)?,
+                })
+            })
+            .collect()
+    }
+}
+
+fn mean(samples: &[f64]) -> f64 {
+    samples.iter().sum::<f64>() / samples.len() as f64
+}
+
+fn std_dev(samples: &[f64], mean: f64) -> f64 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+
+    let squares = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>();
+    (squares / (samples.len() - 1) as f64).sqrt()
+}
+
+/// Returns a percentile of sorted samples, interpolating between ranks
+pub(crate) fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p * (sorted.len() - 1) as f64;
+    let (low, high) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[low] + (sorted[high] - sorted[low]) * (rank - low as f64)
+}
+
+/// Returns the generator of bootstrap resamples
+pub(crate) fn bootstrap_rng() -> StdRng {
+    StdRng::seed_from_u64(BOOTSTRAP_SEED)
+}
+
+/// Fills a buffer of the length of the samples with a resample of them
+pub(crate) fn resample(samples: &[f64], rng: &mut StdRng, resample: &mut [f64]) {
+    for value in resample {
+        *value = samples[rng.gen_range(0..samples.len())];
+    }
+}
+
+/// Returns the median of a resample, reordering it
+pub(crate) fn resample_median(resample: &mut [f64]) -> f64 {
+    // Selecting the middle is much cheaper than sorting every resample. For
+    // an even length, the lower middle is the largest value below it.
+    let len = resample.len();
+    let (lower, &mut upper, _) = resample.select_nth_unstable_by(len / 2, f64::total_cmp);
+    if len % 2 == 1 {
+        return upper;
+    }
+
+    let lower = lower
+        .iter()
+        .copied()
+        .max_by(f64::total_cmp)
+        .unwrap_or(upper);
+    (lower + upper) / 2.0
+}
+
+/// Returns the bootstrap confidence intervals of the mean and the median
+fn bootstrap(samples: &[f64]) -> (Interval, Interval) {
+    let mut rng = bootstrap_rng();
+    let mut buffer = vec![0.0; samples.len()];
+    let mut means = Vec::with_capacity(BOOTSTRAP_RESAMPLES);
+    let mut medians = Vec::with_capacity(BOOTSTRAP_RESAMPLES);
+
+    for _ in 0..BOOTSTRAP_RESAMPLES {
+        resample(samples, &mut rng, &mut buffer);
+        means.push(mean(&buffer));
+        medians.push(resample_median(&mut buffer));
+    }
+
+    (
+        interval(&mut means, CONFIDENCE_LEVEL),
+        interval(&mut medians, CONFIDENCE_LEVEL),
+    )
+}
+
+/// Returns the interval holding a share of the estimates of a bootstrap
+pub(crate) fn interval(estimates: &mut [f64], confidence: f64) -> Interval {
+    estimates.sort_by(f64::total_cmp);
+    let tail = (1.0 - confidence) / 2.0;
+
+    Interval {
+        low: percentile(estimates, tail),
+        high: percentile(estimates, 1.0 - tail),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_TO_TEN: [f64; 10] = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0];
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn percentile_interpolates_between_ranks() {
+        assert_close(percentile(&ONE_TO_TEN, 0.0), 1.0);
+        assert_close(percentile(&ONE_TO_TEN, 0.05), 1.45);
+        assert_close(percentile(&ONE_TO_TEN, 0.25), 3.25);
+        assert_close(percentile(&ONE_TO_TEN, 0.5), 5.5);
+        assert_close(percentile(&ONE_TO_TEN, 0.95), 9.55);
+        assert_close(percentile(&ONE_TO_TEN, 1.0), 10.0);
+        assert_close(percentile(&[4.0], 0.99), 4.0);
+    }
+
+    #[test]
+    fn summary_of_known_samples() {
+        let mut shuffled = ONE_TO_TEN;
+        shuffled.reverse();
+        let summary = Summary::of(&shuffled).unwrap();
+
+        assert_eq!(summary.count, 10);
+        assert_close(summary.mean, 5.5);
+        assert_close(summary.median, 5.5);
+        assert_close(summary.min, 1.0);
+        assert_close(summary.max, 10.0);
+        assert_close(summary.std_dev, (82.5f64 / 9.0).sqrt());
+        assert_close(summary.mad, 2.5);
+        assert_close(summary.p5, 1.45);
+        assert_close(summary.p95, 9.55);
+        assert_close(summary.p99, 9.91);
+        assert_eq!(summary.outliers, Outliers::default());
+
+        assert!(summary.mean_ci.low <= summary.mean && summary.mean <= summary.mean_ci.high);
+        assert!(
+            summary.median_ci.low <= summary.median && summary.median <= summary.median_ci.high
+        );
+        assert_eq!(Summary::of(&ONE_TO_TEN), Some(summary));
+    }
+
+    #[test]
+    fn summary_of_no_or_one_sample() {
+        assert_eq!(Summary::of(&[]), None);
+
+        let summary = Summary::of(&[3.0]).unwrap();
+        assert_close(summary.std_dev, 0.0);
+        assert_close(summary.mad, 0.0);
+        assert_eq!(
+            summary.mean_ci,
+            Interval {
+                low: 3.0,
+                high: 3.0
+            }
+        );
+        assert_eq!(
+            summary.median_ci,
+            Interval {
+                low: 3.0,
+                high: 3.0
+            }
+        );
+    }
+
+    #[test]
+    fn fences_classify_outliers() {
+        let fences = Fences::of(&ONE_TO_TEN);
+        assert_close(fences.low_severe, -10.25);
+        assert_close(fences.low_mild, -3.5);
+        assert_close(fences.high_mild, 14.5);
+        assert_close(fences.high_severe, 21.25);
+
+        assert_eq!(fences.classify(-11.0), Some(Outlier::LowSevere));
+        assert_eq!(fences.classify(-4.0), Some(Outlier::LowMild));
+        assert_eq!(fences.classify(10.0), None);
+        assert_eq!(fences.classify(15.0), Some(Outlier::HighMild));
+        assert_eq!(fences.classify(22.0), Some(Outlier::HighSevere));
+    }
+
+    #[test]
+    fn resample_median_averages_the_middles() {
+        assert_close(resample_median(&mut [3.0, 1.0, 2.0]), 2.0);
+        assert_close(resample_median(&mut [4.0, 1.0, 3.0, 2.0]), 2.5);
+        assert_close(resample_median(&mut [7.0]), 7.0);
+        assert_close(resample_median(&mut [2.0, 2.0, 9.0, 1.0]), 2.0);
+    }
+
+    #[test]
+    fn interval_cuts_both_tails() {
+        let mut estimates = (0..=100).rev().map(f64::from).collect::<Vec<_>>();
+        let interval = interval(&mut estimates, 0.9);
+        assert_close(interval.low, 5.0);
+        assert_close(interval.high, 95.0);
+    }
+}
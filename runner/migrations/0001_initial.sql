@@ -0,0 +1,94 @@
+-- The commits that were benchmarked, by repository.
+CREATE TABLE commits (
+    id         INTEGER PRIMARY KEY,
+    repository TEXT NOT NULL,
+    hash       TEXT NOT NULL,
+    UNIQUE (repository, hash)
+);
+
+-- The environments results were produced in, deduplicated by the hash of
+-- their provenance.
+CREATE TABLE environments (
+    id           INTEGER PRIMARY KEY,
+    hash         TEXT NOT NULL UNIQUE,
+    cpu_model    TEXT NOT NULL,
+    cores        INTEGER NOT NULL,
+    kernel       TEXT NOT NULL,
+    docker       TEXT NOT NULL,
+    rustc        TEXT,
+    runner       TEXT NOT NULL,
+    profile_hash TEXT NOT NULL,
+    provenance   TEXT NOT NULL
+);
+
+-- Every pipeline run, successful or not. A query runs its commit and its
+-- baseline, whose runs share the ID of the query, and a run only replaces an
+-- earlier run of the same query, commit and role, such as before a retry.
+CREATE TABLE runs (
+    id             INTEGER PRIMARY KEY,
+    query_id       TEXT NOT NULL,
+    role           TEXT NOT NULL,
+    commit_id      INTEGER NOT NULL REFERENCES commits (id),
+    environment_id INTEGER NOT NULL REFERENCES environments (id),
+    branch         TEXT,
+    success        INTEGER NOT NULL,
+    failed_stage   TEXT,
+    failed_variant TEXT,
+    recorded       INTEGER NOT NULL,
+    UNIQUE (query_id, commit_id, role)
+);
+
+CREATE INDEX runs_by_commit ON runs (commit_id);
+CREATE INDEX runs_by_branch ON runs (branch, recorded);
+
+-- The outcome of every stage of a run, the variant is null for stages that
+-- are shared by every build variant.
+CREATE TABLE stages (
+    run_id      INTEGER NOT NULL REFERENCES runs (id) ON DELETE CASCADE,
+    name        TEXT NOT NULL,
+    variant     TEXT,
+    outcome     TEXT NOT NULL,
+    exitcode    INTEGER NOT NULL,
+    wall_time   REAL NOT NULL,
+    cpu_time    REAL NOT NULL,
+    peak_memory INTEGER NOT NULL
+);
+
+CREATE INDEX stages_by_run ON stages (run_id);
+
+-- The raw measurements of every metric of every sample, in the order they
+-- were taken, without those that were not finite numbers.
+CREATE TABLE measurements (
+    run_id   INTEGER NOT NULL REFERENCES runs (id) ON DELETE CASCADE,
+    variant  TEXT NOT NULL,
+    sample   TEXT NOT NULL,
+    metric   TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    value    REAL NOT NULL,
+    PRIMARY KEY (run_id, variant, sample, metric, position)
+);
+
+-- The statistics of every metric of every sample, which are null where they
+-- were not finite numbers.
+CREATE TABLE summaries (
+    run_id      INTEGER NOT NULL REFERENCES runs (id) ON DELETE CASCADE,
+    variant     TEXT NOT NULL,
+    sample      TEXT NOT NULL,
+    metric      TEXT NOT NULL,
+    count       INTEGER NOT NULL,
+    mean        REAL,
+    median      REAL,
+    min         REAL,
+    max         REAL,
+    std_dev     REAL,
+    mad         REAL,
+    p5          REAL,
+    p95         REAL,
+    p99         REAL,
+    outliers    INTEGER NOT NULL,
+    mean_low    REAL,
+    mean_high   REAL,
+    median_low  REAL,
+    median_high REAL,
+    PRIMARY KEY (run_id, variant, sample, metric)
+);
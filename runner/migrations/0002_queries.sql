@@ -0,0 +1,8 @@
+-- The queries submitted through the HTTP API, such that they are known
+-- before a worker recorded their runs and their IDs are never reused.
+CREATE TABLE queries (
+    id         TEXT PRIMARY KEY,
+    repository TEXT NOT NULL,
+    hash       TEXT NOT NULL,
+    submitted  INTEGER NOT NULL
+);
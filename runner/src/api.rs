@@ -0,0 +1,434 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use typster_proto::{
+    comparison::Test, BenchQuery, BuildVariant, Comparison, Provenance, SampleComparison,
+    SampleMetric, Thresholds,
+};
+
+use crate::{
+    config::ServeArgs,
+    database::{Database, RunFilter, RunRecord, SummaryRecord},
+    profile::Profile,
+    provenance,
+    queue::{
+        amqp::AmqpSubmitter,
+        spool::{self, SpoolStatus},
+    },
+    runs::random_id,
+};
+
+/// Serves the HTTP API until the process is stopped.
+///
+/// Queries are submitted to the embedded spool when one is configured and to
+/// the AMQP broker otherwise, everything else is answered from the database.
+pub async fn serve(profile: &Profile, args: &ServeArgs) -> anyhow::Result<()> {
+    let Some(settings) = &profile.database else {
+        anyhow::bail!("the HTTP API needs a database in the profile");
+    };
+
+    let submitter = match &args.spool {
+        Some(spool) => Submitter::Spool(spool.clone()),
+        None => Submitter::Amqp(Box::new(
+            AmqpSubmitter::connect(&args.amqp_addr, &args.queue).await?,
+        )),
+    };
+
+    let api = Arc::new(Api {
+        database: Database::open(&settings.path).await?,
+        submitter,
+    });
+
+    let router = Router::new()
+        .route("/queries", post(submit))
+        .route("/queries/:id", get(query_status))
+        .route("/runs", get(list_runs))
+        .route("/runs/:id", get(run))
+        .route("/commits/:commit/results", get(commit_results))
+        .route("/compare", get(compare))
+        .with_state(api);
+
+    let listener = tokio::net::TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("failed to listen on {}", args.listen))?;
+    tracing::info!("Serving HTTP API on {}", args.listen);
+
+    axum::serve(listener, router)
+        .await
+        .context("HTTP server failed")
+}
+
+struct Api {
+    /// The database, which also holds the queries submitted through the API
+    /// until a worker recorded their runs
+    database: Database,
+    submitter: Submitter,
+}
+
+/// Where queries are submitted to.
+enum Submitter {
+    Spool(PathBuf),
+    Amqp(Box<AmqpSubmitter>),
+}
+
+/// An error answered with a status code and a JSON message.
+struct ApiError(StatusCode, String);
+
+/// The status of a query.
+#[derive(Serialize)]
+struct QueryStatus {
+    id: String,
+    state: QueryState,
+
+    /// The recorded runs of the commit and its baseline, once the query
+    /// finished
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    runs: Vec<RunRecord>,
+
+    /// Where the query is in the spool, while it is in it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spool: Option<SpoolStatus>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum QueryState {
+    /// The query was submitted, but has not been recorded yet
+    Submitted,
+    Pending,
+    Running,
+    Dead,
+    Succeeded,
+    Failed,
+}
+
+/// A recorded run with the statistics of its samples.
+#[derive(Serialize)]
+struct RunResults {
+    run: RunRecord,
+    summaries: Vec<SummaryRecord>,
+}
+
+#[derive(Deserialize)]
+struct RunsParams {
+    query: Option<String>,
+    repository: Option<String>,
+    commit: Option<String>,
+    branch: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct CompareParams {
+    repository: String,
+    baseline: String,
+    candidate: String,
+    alpha: Option<f64>,
+    min_change: Option<f64>,
+    test: Option<String>,
+}
+
+/// How the latest successful runs of two commits compare.
+#[derive(Serialize)]
+struct CompareResults {
+    baseline: ComparedRun,
+    candidate: ComparedRun,
+
+    /// Whether both runs ran on the same kind of host with the same profile,
+    /// without which their differences say little about the commits
+    comparable: bool,
+
+    comparisons: Vec<SampleComparison>,
+}
+
+/// A compared run with where and how it was produced.
+#[derive(Serialize)]
+struct ComparedRun {
+    #[serde(flatten)]
+    run: RunRecord,
+    provenance: Provenance,
+}
+
+/// Adds a query to the queue, generating its ID if it has none
+async fn submit(
+    State(api): State<Arc<Api>>,
+    Json(mut body): Json<serde_json::Value>,
+) -> Result<(StatusCode, Json<QueryStatus>), ApiError> {
+    if let Some(fields) = body.as_object_mut() {
+        if fields.get("id").is_none_or(serde_json::Value::is_null) {
+            fields.insert("id".into(), random_id(10).into());
+        }
+    }
+
+    let query: BenchQuery = serde_json::from_value(body)
+        .map_err(|err| ApiError::bad_request(format!("invalid query: {}", err)))?;
+
+    if !BenchQuery::is_valid_id(&query.id) {
+        return Err(ApiError::bad_request(
+            "query IDs must be 1 to 64 letters, digits, `-` or `_`",
+        ));
+    }
+
+    let conflict = || ApiError::conflict(format!("query `{}` already exists", query.id));
+    if let Submitter::Spool(root) = &api.submitter {
+        if spool::status(root, &query.id).await?.is_some() {
+            return Err(conflict());
+        }
+    }
+
+    if !api.database.add_query(&query).await? {
+        return Err(conflict());
+    }
+
+    let submitted = match &api.submitter {
+        Submitter::Spool(root) => spool::submit(root, &query).await,
+        Submitter::Amqp(submitter) => submitter.submit(&query).await,
+    };
+
+    if let Err(err) = submitted {
+        if let Err(err) = api.database.remove_query(&query.id).await {
+            tracing::warn!("{:#}", err);
+        }
+
+        return Err(err.into());
+    }
+
+    tracing::info!("submitted query {}", query.id);
+
+    let status = QueryStatus {
+        id: query.id,
+        state: QueryState::Submitted,
+        runs: Vec::new(),
+        spool: None,
+    };
+
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+/// Returns the status of a query, from the database once it was recorded
+/// and from the spool while it is queued
+async fn query_status(
+    State(api): State<Arc<Api>>,
+    Path(id): Path<String>,
+) -> Result<Json<QueryStatus>, ApiError> {
+    let filter = RunFilter {
+        query_id: Some(id.clone()),
+        ..Default::default()
+    };
+
+    let runs = api.database.runs(filter).await?;
+    if !runs.is_empty() {
+        let state = match runs.iter().all(|run| run.success) {
+            true => QueryState::Succeeded,
+            false => QueryState::Failed,
+        };
+
+        return Ok(Json(QueryStatus {
+            id,
+            state,
+            runs,
+            spool: None,
+        }));
+    }
+
+    if let Submitter::Spool(root) = &api.submitter {
+        if let Some(status) = spool::status(root, &id).await? {
+            let state = match status.state {
+                spool::SpoolState::Pending => QueryState::Pending,
+                spool::SpoolState::Running => QueryState::Running,
+                spool::SpoolState::Dead => QueryState::Dead,
+            };
+
+            return Ok(Json(QueryStatus {
+                id,
+                state,
+                runs: Vec::new(),
+                spool: Some(status),
+            }));
+        }
+    }
+
+    if api.database.has_query(&id).await? {
+        return Ok(Json(QueryStatus {
+            id,
+            state: QueryState::Submitted,
+            runs: Vec::new(),
+            spool: None,
+        }));
+    }
+
+    Err(ApiError::not_found(format!("unknown query `{}`", id)))
+}
+
+/// Lists the recorded runs, most recent first
+async fn list_runs(
+    State(api): State<Arc<Api>>,
+    Query(params): Query<RunsParams>,
+) -> Result<Json<Vec<RunRecord>>, ApiError> {
+    let filter = RunFilter {
+        query_id: params.query,
+        repository: params.repository,
+        commit: params.commit,
+        branch: params.branch,
+        limit: Some(params.limit.unwrap_or(100)),
+    };
+
+    Ok(Json(api.database.runs(filter).await?))
+}
+
+/// Returns a recorded run with its statistics
+async fn run(
+    State(api): State<Arc<Api>>,
+    Path(id): Path<i64>,
+) -> Result<Json<RunResults>, ApiError> {
+    let Some(run) = api.database.run(id).await? else {
+        return Err(ApiError::not_found(format!("unknown run {}", id)));
+    };
+
+    let summaries = api.database.summaries(id).await?;
+    Ok(Json(RunResults { run, summaries }))
+}
+
+/// Returns every recorded run of a commit with its statistics, most recent
+/// first
+async fn commit_results(
+    State(api): State<Arc<Api>>,
+    Path(commit): Path<String>,
+) -> Result<Json<Vec<RunResults>>, ApiError> {
+    let filter = RunFilter {
+        commit: Some(commit),
+        ..Default::default()
+    };
+
+    let mut results = Vec::new();
+    for run in api.database.runs(filter).await? {
+        let summaries = api.database.summaries(run.id).await?;
+        results.push(RunResults { run, summaries });
+    }
+
+    Ok(Json(results))
+}
+
+/// Compares the latest successful runs of two commits of a repository
+async fn compare(
+    State(api): State<Arc<Api>>,
+    Query(params): Query<CompareParams>,
+) -> Result<Json<CompareResults>, ApiError> {
+    let defaults = Thresholds::default();
+    let thresholds = Thresholds {
+        alpha: params.alpha.unwrap_or(defaults.alpha),
+        min_change: params.min_change.unwrap_or(defaults.min_change),
+        test: match &params.test {
+            Some(test) => test.parse::<Test>().map_err(ApiError::bad_request)?,
+            None => defaults.test,
+        },
+    };
+    thresholds.validate().map_err(ApiError::bad_request)?;
+
+    let baseline = latest_success(&api.database, &params.repository, &params.baseline).await?;
+    let candidate = latest_success(&api.database, &params.repository, &params.candidate).await?;
+    let base_series = api.database.measurements(baseline.run.id).await?;
+    let candidate_series = api.database.measurements(candidate.run.id).await?;
+
+    let mut comparisons = Vec::new();
+    for base in &base_series {
+        let Some(other) = candidate_series.iter().find(|other| {
+            other.variant == base.variant
+                && other.sample == base.sample
+                && other.metric == base.metric
+        }) else {
+            continue;
+        };
+
+        let (Ok(variant), Ok(metric)) = (
+            base.variant.parse::<BuildVariant>(),
+            base.metric.parse::<SampleMetric>(),
+        ) else {
+            continue;
+        };
+
+        if let Some(comparison) = Comparison::of(&base.values, &other.values, metric, &thresholds) {
+            comparisons.push(SampleComparison {
+                variant,
+                sample: base.sample.clone(),
+                metric,
+                comparison,
+            });
+        }
+    }
+
+    Ok(Json(CompareResults {
+        comparable: provenance::environment(&baseline.provenance)
+            == provenance::environment(&candidate.provenance),
+        baseline,
+        candidate,
+        comparisons,
+    }))
+}
+
+/// Returns the most recent successful run of a commit of a repository
+async fn latest_success(
+    database: &Database,
+    repository: &str,
+    commit: &str,
+) -> Result<ComparedRun, ApiError> {
+    let filter = RunFilter {
+        repository: Some(repository.to_owned()),
+        commit: Some(commit.to_owned()),
+        ..Default::default()
+    };
+
+    let run = database
+        .runs(filter)
+        .await?
+        .into_iter()
+        .find(|run| run.success)
+        .ok_or_else(|| ApiError::not_found(format!("no successful run of commit `{}`", commit)))?;
+
+    let provenance = database
+        .provenance(run.id)
+        .await?
+        .context("recorded run has no environment")?;
+
+    Ok(ComparedRun { run, provenance })
+}
+
+impl ApiError {
+    fn not_found(message: impl Into<String>) -> Self {
+        Self(StatusCode::NOT_FOUND, message.into())
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self(StatusCode::BAD_REQUEST, message.into())
+    }
+
+    fn conflict(message: impl Into<String>) -> Self {
+        Self(StatusCode::CONFLICT, message.into())
+    }
+}
+
+/// Internal errors are only logged, as they may reveal paths or addresses
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        tracing::error!("HTTP request failed: {:#}", err);
+        Self(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal server error".into(),
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": self.1 });
+        (self.0, Json(body)).into_response()
+    }
+}
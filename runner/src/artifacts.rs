@@ -0,0 +1,342 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use bollard::Docker;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use typster_proto::Stage;
+
+use crate::{
+    profile::{ArtifactSettings, CacheSettings, PipelineStage, Samples},
+    sandbox::Sandbox,
+};
+
+/// The name of the metadata file of every cache entry
+const META_FILE: &str = "meta.json";
+
+/// A local store of built binaries, shared between sandboxes.
+///
+/// Entries are addressed by the hash of an [`ArtifactKey`] and evicted in
+/// least-recently-used order once the store exceeds its limits.
+#[derive(Debug, Clone)]
+pub struct ArtifactStore {
+    root: PathBuf,
+    settings: ArtifactSettings,
+}
+
+/// Everything that influences the output of a build.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ArtifactKey {
+    /// The repository URL
+    pub repository: String,
+
+    /// The commit hash
+    pub commit: String,
+
+    /// The digest of the image the build runs in
+    pub image_digest: String,
+
+    /// The build variant, such as `plain` or `pgo`
+    pub variant: String,
+
+    /// The PGO training files, empty for builds that do not use PGO
+    pub training: Vec<PathBuf>,
+
+    /// The SHA-256 hashes of the contents of the training files, in the
+    /// same order
+    #[serde(default)]
+    pub training_hashes: Vec<String>,
+
+    /// The environment of the image that affects compilation, such as
+    /// `RUSTFLAGS`. Flags set by the build scripts are covered by the image
+    /// digest.
+    #[serde(default)]
+    pub build_env: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ArtifactMeta {
+    key: ArtifactKey,
+    size: u64,
+    created: u64,
+    last_used: u64,
+
+    /// The version of rustc that built the artifacts, if it was reported
+    #[serde(default)]
+    rustc: Option<String>,
+}
+
+impl ArtifactKey {
+    /// Builds the key of the artifacts of a pipeline stage
+    pub async fn new(
+        docker: &Docker,
+        sandbox: &Sandbox,
+        stage: &PipelineStage,
+        image: &str,
+        samples: &Samples,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(variant) = &stage.cache else {
+            return Ok(None);
+        };
+
+        let inspect = docker
+            .inspect_image(image)
+            .await
+            .with_context(|| format!("failed to inspect image `{}`", image))?;
+        let image_digest = inspect
+            .id
+            .with_context(|| format!("image `{}` has no digest", image))?;
+
+        let mut build_env = inspect
+            .config
+            .and_then(|config| config.env)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|var| var.starts_with("RUST") || var.starts_with("CARGO_"))
+            .collect::<Vec<_>>();
+        build_env.sort();
+
+        let training = if stage.stage == Stage::PgoBuild {
+            samples.training.clone()
+        } else {
+            Vec::new()
+        };
+
+        let mut training_hashes = Vec::with_capacity(training.len());
+        for file in &training {
+            let path = samples.root.join(file);
+            let data = tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("failed to read training file {}", path.display()))?;
+            training_hashes.push(format!("{:x}", Sha256::digest(data)));
+        }
+
+        Ok(Some(Self {
+            repository: sandbox.repository.clone(),
+            commit: sandbox.commit.clone(),
+            image_digest,
+            variant: variant.clone(),
+            training,
+            training_hashes,
+            build_env,
+        }))
+    }
+
+    /// Returns the hex-encoded hash that addresses the key in the store
+    pub fn hash(&self) -> String {
+        let data = serde_json::to_vec(self).expect("failed to serialize artifact key");
+        format!("{:x}", Sha256::digest(data))
+    }
+}
+
+/// The files that make up the artifacts of a build, as their name in the
+/// store and their location in the sandbox.
+fn artifact_files(sandbox: &Sandbox) -> [(&'static str, PathBuf, bool); 2] {
+    [
+        ("typst", sandbox.git.join("target/release/typst"), true),
+        (
+            "merged.profdata",
+            sandbox.pgo_data.join("merged.profdata"),
+            false,
+        ),
+    ]
+}
+
+impl ArtifactStore {
+    /// Opens the artifact store of the cache, creating it if needed
+    pub async fn open(cache: &CacheSettings) -> anyhow::Result<Self> {
+        let root = cache.root.join("artifacts");
+        tokio::fs::create_dir_all(&root)
+            .await
+            .context("failed to create artifact cache directory")?;
+
+        Ok(Self {
+            root,
+            settings: cache.artifacts.clone(),
+        })
+    }
+
+    /// Whether the store holds the artifacts of a key
+    pub async fn contains(&self, key: &ArtifactKey) -> bool {
+        tokio::fs::try_exists(self.root.join(key.hash()).join(META_FILE))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Copies the artifacts of a key into the sandbox, returning whether
+    /// they were found
+    pub async fn restore(&self, key: &ArtifactKey, sandbox: &Sandbox) -> anyhow::Result<bool> {
+        let entry = self.root.join(key.hash());
+        let Some(mut meta) = read_meta(&entry).await? else {
+            return Ok(false);
+        };
+
+        for (name, target, _) in artifact_files(sandbox) {
+            let source = entry.join(name);
+            if !tokio::fs::try_exists(&source).await.unwrap_or(false) {
+                continue;
+            }
+
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .context("failed to create artifact directory")?;
+            }
+
+            tokio::fs::copy(&source, &target)
+                .await
+                .with_context(|| format!("failed to restore artifact `{}`", name))?;
+        }
+
+        meta.last_used = now();
+        write_meta(&entry, &meta).await?;
+
+        Ok(true)
+    }
+
+    /// Returns the version of rustc that built the artifacts of a key, if it
+    /// is known
+    pub async fn rustc(&self, key: &ArtifactKey) -> anyhow::Result<Option<String>> {
+        let meta = read_meta(&self.root.join(key.hash())).await?;
+        Ok(meta.and_then(|meta| meta.rustc))
+    }
+
+    /// Copies the artifacts of a successful build into the store, then
+    /// evicts entries until the store is within its limits
+    pub async fn store(
+        &self,
+        key: &ArtifactKey,
+        sandbox: &Sandbox,
+        rustc: Option<String>,
+    ) -> anyhow::Result<()> {
+        let entry = self.root.join(key.hash());
+        if read_meta(&entry).await?.is_some() {
+            return Ok(());
+        }
+
+        // Fill a temporary directory first so that concurrent readers never
+        // observe a partially written entry.
+        let suffix: u32 = rand::thread_rng().gen();
+        let tmp = self.root.join(format!(".tmp-{:08x}", suffix));
+        tokio::fs::create_dir_all(&tmp)
+            .await
+            .context("failed to create artifact cache entry")?;
+
+        let mut size = 0;
+        for (name, source, required) in artifact_files(sandbox) {
+            if !tokio::fs::try_exists(&source).await.unwrap_or(false) {
+                if required {
+                    tokio::fs::remove_dir_all(&tmp).await.ok();
+                    anyhow::bail!("missing build artifact `{}`", source.display());
+                }
+
+                continue;
+            }
+
+            size += tokio::fs::copy(&source, tmp.join(name))
+                .await
+                .with_context(|| format!("failed to store artifact `{}`", name))?;
+        }
+
+        let now = now();
+        write_meta(
+            &tmp,
+            &ArtifactMeta {
+                key: key.clone(),
+                size,
+                created: now,
+                last_used: now,
+                rustc,
+            },
+        )
+        .await?;
+
+        if let Err(err) = tokio::fs::rename(&tmp, &entry).await {
+            // Another sandbox stored the same artifacts in the meantime.
+            tokio::fs::remove_dir_all(&tmp).await.ok();
+            if read_meta(&entry).await?.is_none() {
+                return Err(err).context("failed to store artifact cache entry");
+            }
+        }
+
+        self.evict().await
+    }
+
+    /// Removes the least recently used entries until the store is within
+    /// its size and entry limits
+    pub async fn evict(&self) -> anyhow::Result<()> {
+        let mut entries = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.root)
+            .await
+            .context("failed to read artifact cache directory")?;
+
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            match read_meta(&path).await {
+                Ok(Some(meta)) => entries.push((path, meta)),
+                _ => tracing::warn!("ignoring invalid artifact cache entry {}", path.display()),
+            }
+        }
+
+        entries.sort_by_key(|(_, meta)| meta.last_used);
+
+        let mut size: u64 = entries.iter().map(|(_, meta)| meta.size).sum();
+        let mut count = entries.len();
+        for (path, meta) in entries {
+            if count <= self.settings.max_entries && size <= self.settings.max_size.as_u64() {
+                break;
+            }
+
+            tracing::info!(
+                "evicting artifacts of {} ({})",
+                meta.key.commit,
+                meta.key.variant
+            );
+            tokio::fs::remove_dir_all(&path)
+                .await
+                .with_context(|| format!("failed to evict {}", path.display()))?;
+
+            size -= meta.size;
+            count -= 1;
+        }
+
+        Ok(())
+    }
+}
+
+async fn read_meta(entry: &Path) -> anyhow::Result<Option<ArtifactMeta>> {
+    let path = entry.join(META_FILE);
+    if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let data = tokio::fs::read(&path)
+        .await
+        .context("failed to read artifact metadata")?;
+
+    serde_json::from_slice(&data)
+        .context("failed to parse artifact metadata")
+        .map(Some)
+}
+
+async fn write_meta(entry: &Path, meta: &ArtifactMeta) -> anyhow::Result<()> {
+    let data = serde_json::to_vec_pretty(meta).context("failed to serialize artifact metadata")?;
+    tokio::fs::write(entry.join(META_FILE), data)
+        .await
+        .context("failed to write artifact metadata")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,518 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use bollard::Docker;
+use futures_util::{stream, StreamExt, TryFutureExt};
+use tracing::Instrument;
+use typster_proto::{compare_results, BenchQuery, BenchResult, Comparison, Thresholds, Verdict};
+
+use crate::{
+    config::{CleanArgs, CompareArgs, HistoryArgs, ReportArgs, RunArgs, RunsArgs, SubmitArgs},
+    database::{Database, RunFilter, RunRole},
+    history::HistoryStore,
+    pipeline::{Pipeline, PipelineState},
+    profile::{BenchOptions, Profile},
+    provenance,
+    queue::spool,
+    results::SamplingResults,
+    runs::random_id,
+    sandbox::Sandbox,
+    scheduler::CoreAllocator,
+    worker::collect_result,
+};
+
+/// Runs the pipeline on every commit, or resumes it in an existing sandbox
+pub async fn run(profile: &Profile, docker: &Docker, args: &RunArgs) -> anyhow::Result<()> {
+    let pipeline = Pipeline::from_profile(profile).await?;
+    let options = args.bench.options(profile)?;
+    let cores = CoreAllocator::from_profile(profile)?;
+    let database = match &profile.database {
+        Some(settings) => Some(Database::open(&settings.path).await?),
+        None => None,
+    };
+    let database = database.as_ref();
+
+    if let Some(id) = &args.sandbox {
+        let sandbox = Sandbox::open(profile, &profile.workdir, id)
+            .await?
+            .with_cores(cores);
+
+        if let Some(from) = &args.from {
+            let mut state = PipelineState::load(&sandbox).await?;
+            pipeline.invalidate_from(&mut state, from)?;
+            state.save(&sandbox).await?;
+        }
+
+        return run_sandbox(&pipeline, &sandbox, profile, docker, &options, database).await;
+    }
+
+    if args.id.is_some() && args.commits.len() > 1 {
+        bail!("`--id` can only be used with a single commit");
+    }
+
+    let failures = stream::iter(&args.commits)
+        .map(|commit| {
+            let pipeline = &pipeline;
+            let options = &options;
+            let cores = cores.clone();
+            async move {
+                let sandbox = Sandbox::new(
+                    profile,
+                    &profile.workdir,
+                    &args.repo,
+                    commit,
+                    args.id.clone(),
+                )
+                .await?
+                .with_cores(cores);
+
+                run_sandbox(pipeline, &sandbox, profile, docker, options, database).await
+            }
+            .map_err(move |err| err.context(format!("failed to benchmark commit {}", commit)))
+        })
+        .buffer_unordered(args.bench.jobs.max(1))
+        .filter_map(|result| async move { result.err() })
+        .collect::<Vec<_>>()
+        .await;
+
+    for failure in &failures {
+        tracing::error!("{:#}", failure);
+    }
+
+    if !failures.is_empty() {
+        bail!(
+            "{} of {} commits failed",
+            failures.len(),
+            args.commits.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs the pipeline in a sandbox, recording it in the database if there is
+/// one, and failing if any stage failed
+async fn run_sandbox(
+    pipeline: &Pipeline,
+    sandbox: &Sandbox,
+    profile: &Profile,
+    docker: &Docker,
+    options: &BenchOptions,
+    database: Option<&Database>,
+) -> anyhow::Result<()> {
+    let span = tracing::info_span!("sandbox", id = %sandbox.id);
+    let report = pipeline
+        .execute(sandbox, profile, docker, options)
+        .instrument(span)
+        .await?;
+
+    // The provenance is recorded even for failed pipelines, whose partial
+    // results may still be inspected.
+    let provenance = provenance::collect(docker, profile, &report).await;
+    let data = serde_json::to_vec_pretty(&provenance).context("failed to serialize provenance")?;
+    tokio::fs::write(sandbox.provenance_file(), data)
+        .await
+        .context("failed to write provenance")?;
+
+    let failed = report.failed.clone();
+    if let Some(database) = database {
+        let result = collect_result(sandbox, &options.samples, report, provenance).await?;
+        database
+            .record(
+                &sandbox.id,
+                RunRole::Candidate,
+                &sandbox.repository,
+                &sandbox.commit,
+                None,
+                &result,
+            )
+            .await?;
+    }
+
+    if let Some(stage) = failed {
+        bail!("Pipeline failed at stage `{}`", stage);
+    }
+
+    tracing::info!("Pipeline done, results in {}", sandbox.parent.display());
+
+    Ok(())
+}
+
+/// Adds a benchmark query to an embedded queue
+pub async fn submit(args: &SubmitArgs) -> anyhow::Result<()> {
+    let query = BenchQuery {
+        samples: args.samples.clone(),
+        tags: args.tags.clone(),
+        profile: args.bench_profile.clone(),
+        variants: args.variants.clone(),
+        baseline: args.baseline.clone(),
+        priority: args.priority,
+        branch: args.branch.clone(),
+        ..BenchQuery::new(
+            args.id.clone().unwrap_or_else(|| random_id(10)),
+            args.repo.clone(),
+            args.commit.clone(),
+        )
+    };
+
+    if !BenchQuery::is_valid_id(&query.id) {
+        bail!("query IDs must be 1 to 64 letters, digits, `-` or `_`");
+    }
+
+    if spool::status(&args.spool, &query.id).await?.is_some() {
+        bail!("query `{}` is already in the spool", query.id);
+    }
+
+    spool::submit(&args.spool, &query).await?;
+    println!("{}", query.id);
+
+    Ok(())
+}
+
+/// Compares every metric of two runs, grouping them by whether they got
+/// significantly faster, slower or did not detectably change
+pub async fn compare(args: &CompareArgs) -> anyhow::Result<()> {
+    let thresholds = Thresholds {
+        alpha: args.alpha,
+        min_change: args.min_change,
+        test: args.test,
+    };
+    thresholds.validate().map_err(anyhow::Error::msg)?;
+
+    let baseline = Compared::load(&args.baseline).await?;
+    let candidate = Compared::load(&args.candidate).await?;
+    let mut rows = match (baseline, candidate) {
+        (Compared::Result(baseline), Compared::Result(candidate)) => {
+            compare_results(&baseline, &candidate, &thresholds)
+                .into_iter()
+                .map(|c| {
+                    let label = format!("{} {} ({})", c.sample, c.metric, c.variant);
+                    (label, c.comparison)
+                })
+                .collect()
+        }
+        (Compared::Samples(baseline), Compared::Samples(candidate)) => {
+            compare_samples(&baseline, &candidate, &thresholds)
+        }
+        _ => bail!("cannot compare a benchmark result with sample results"),
+    };
+
+    rows.sort_by(|(a, x), (b, y)| x.verdict.cmp(&y.verdict).then_with(|| a.cmp(b)));
+
+    for verdict in [
+        Verdict::Faster,
+        Verdict::Slower,
+        Verdict::Lower,
+        Verdict::Higher,
+        Verdict::NoChange,
+    ] {
+        let group = rows.iter().filter(|(_, c)| c.verdict == verdict);
+        println!("{} ({}):", verdict, group.clone().count());
+        for (label, comparison) in group {
+            println!(
+                "  {}: {:.3} -> {:.3}, {:+.2}% [{:+.2}%, {:+.2}%], p = {:.4}",
+                label,
+                comparison.baseline,
+                comparison.candidate,
+                comparison.change() * 100.0,
+                (comparison.ratio_ci.low - 1.0) * 100.0,
+                (comparison.ratio_ci.high - 1.0) * 100.0,
+                comparison.p_value,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The results of a run to compare.
+enum Compared {
+    /// A benchmark result, as published by a worker
+    Result(Box<BenchResult>),
+
+    /// The results of the samples, as written by the benchmarks
+    Samples(Vec<(String, SamplingResults)>),
+}
+
+impl Compared {
+    /// Loads a benchmark result from a JSON file, or otherwise the results
+    /// of the samples in a directory or file
+    async fn load(path: &Path) -> anyhow::Result<Self> {
+        if tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .is_file()
+        {
+            let data = tokio::fs::read(path)
+                .await
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            if let Ok(result) = serde_json::from_slice(&data) {
+                return Ok(Self::Result(Box::new(result)));
+            }
+        }
+
+        SamplingResults::load_all(path).await.map(Self::Samples)
+    }
+}
+
+/// Compares every metric of the samples two runs have in common
+fn compare_samples(
+    baseline: &[(String, SamplingResults)],
+    candidate: &[(String, SamplingResults)],
+    thresholds: &Thresholds,
+) -> Vec<(String, Comparison)> {
+    let mut rows = Vec::new();
+    for (name, base) in baseline {
+        let Some((_, other)) = candidate.iter().find(|(other, _)| other == name) else {
+            tracing::warn!("{} is missing from the candidate", name);
+            continue;
+        };
+
+        for results in &base.samples {
+            let Some(samples) = other.metric(results.metric) else {
+                continue;
+            };
+
+            let metric = results.metric.into();
+            if let Some(comparison) = Comparison::of(&results.samples, samples, metric, thresholds)
+            {
+                rows.push((format!("{} {:?}", name, results.metric), comparison));
+            }
+        }
+    }
+
+    rows
+}
+
+/// Prints a summary of every metric of a run
+pub async fn report(profile: &Profile, args: &ReportArgs) -> anyhow::Result<()> {
+    let target = PathBuf::from(&args.target);
+    let path = if tokio::fs::try_exists(&target).await.unwrap_or(false) {
+        target
+    } else {
+        profile.workdir.join(&args.target).join("results")
+    };
+
+    for (name, results) in SamplingResults::load_all(&path).await? {
+        println!("{}:", name);
+        for results in &results.samples {
+            let Some(summary) = results.summary() else {
+                println!("  {:?}: no samples", results.metric);
+                continue;
+            };
+
+            println!(
+                "  {:?}: n = {}, mean = {:.3} [{:.3}, {:.3}], median = {:.3} [{:.3}, {:.3}]",
+                results.metric,
+                summary.count,
+                summary.mean,
+                summary.mean_ci.low,
+                summary.mean_ci.high,
+                summary.median,
+                summary.median_ci.low,
+                summary.median_ci.high,
+            );
+            println!(
+                "    std dev = {:.3}, mad = {:.3}, p5 = {:.3}, p95 = {:.3}, p99 = {:.3}, outliers = {}",
+                summary.std_dev,
+                summary.mad,
+                summary.p5,
+                summary.p95,
+                summary.p99,
+                summary.outliers.total(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints every change point in the history of a branch, marking those
+/// that are regressions
+pub async fn history(profile: &Profile, args: &HistoryArgs) -> anyhow::Result<()> {
+    let Some(settings) = &profile.history else {
+        bail!("the profile does not track the history of branches");
+    };
+
+    let store = HistoryStore::open(settings).await?;
+    let history = store.load(&args.branch).await?;
+    println!("{}: {} commits", history.branch, history.runs.len());
+
+    for change in history.changes(settings) {
+        let kind = if change.change.change() >= settings.min_change {
+            "regression"
+        } else if change.change.change() < 0.0 {
+            "improvement"
+        } else {
+            "change"
+        };
+
+        println!(
+            "  {} {} ({}, {}) at {}: {:.3} -> {:.3}, {:+.2}%, confidence = {:.1}% [{}]",
+            change.key.sample,
+            change.key.metric,
+            change.key.variant,
+            change.key.environment,
+            change.run.commit,
+            change.change.before,
+            change.change.after,
+            change.change.change() * 100.0,
+            change.change.confidence() * 100.0,
+            kind,
+        );
+    }
+
+    Ok(())
+}
+
+/// Lists the runs recorded in the database, or prints the summaries of one
+pub async fn runs(profile: &Profile, args: &RunsArgs) -> anyhow::Result<()> {
+    let Some(settings) = &profile.database else {
+        bail!("the profile does not record runs in a database");
+    };
+
+    let database = Database::open(&settings.path).await?;
+    if let Some(id) = args.id {
+        let Some(run) = database.run(id).await? else {
+            bail!("no run with ID {}", id);
+        };
+
+        let value = |value: Option<f64>| value.map_or("-".to_owned(), |v| format!("{:.3}", v));
+        println!(
+            "{} of query {} ({} at {})",
+            run.id, run.query_id, run.repository, run.commit
+        );
+        for summary in database.summaries(id).await? {
+            println!(
+                "  {} {} ({}): n = {}, mean = {}, median = {} [{}, {}]",
+                summary.sample,
+                summary.metric,
+                summary.variant,
+                summary.count,
+                value(summary.mean),
+                value(summary.median),
+                value(summary.median_low),
+                value(summary.median_high),
+            );
+        }
+
+        return Ok(());
+    }
+
+    let filter = RunFilter {
+        query_id: args.query.clone(),
+        repository: args.repository.clone(),
+        commit: args.commit.clone(),
+        branch: args.branch.clone(),
+        limit: Some(args.limit),
+    };
+
+    for run in database.runs(filter).await? {
+        let outcome = match &run.failed_stage {
+            None => "success".to_owned(),
+            Some(stage) => format!("failed at {}", stage),
+        };
+
+        println!(
+            "{} {} {} {} {} {}",
+            run.id,
+            run.query_id,
+            run.role.as_str(),
+            run.commit,
+            run.branch.as_deref().unwrap_or("-"),
+            outcome
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes sandboxes from the working directory
+pub async fn clean(profile: &Profile, args: &CleanArgs) -> anyhow::Result<()> {
+    let sandboxes = if args.all {
+        let mut sandboxes = Vec::new();
+        let mut entries = tokio::fs::read_dir(&profile.workdir)
+            .await
+            .context("failed to read working directory")?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            if is_sandbox(&entry.path()).await {
+                sandboxes.push(entry.path());
+            }
+        }
+
+        sandboxes
+    } else {
+        // IDs are joined onto the working directory, so paths must not get through.
+        if let Some(id) = args.ids.iter().find(|id| !BenchQuery::is_valid_id(id)) {
+            bail!("invalid sandbox ID `{}`", id);
+        }
+
+        args.ids.iter().map(|id| profile.workdir.join(id)).collect()
+    };
+
+    for sandbox in sandboxes {
+        if !is_sandbox(&sandbox).await {
+            tracing::warn!("not a sandbox: {}", sandbox.display());
+            continue;
+        }
+
+        tokio::fs::remove_dir_all(&sandbox)
+            .await
+            .with_context(|| format!("failed to remove {}", sandbox.display()))?;
+
+        tracing::info!("removed {}", sandbox.display());
+    }
+
+    Ok(())
+}
+
+/// Checks that the profile is valid and prints the pipeline
+pub async fn config_check(profile: &Profile) -> anyhow::Result<()> {
+    let pipeline = Pipeline::new(&profile.pipeline)?;
+
+    if let Some(cores) = &profile.cores {
+        let allocator = CoreAllocator::new(cores)?;
+        for stage in pipeline.stages() {
+            let count = profile.stages.get(stage.stage).exclusive_cores;
+            if let Some(count) = count.filter(|&count| count > allocator.exclusive()) {
+                bail!(
+                    "stage `{}` needs {} exclusive cores, but only {} exist",
+                    stage.name,
+                    count,
+                    allocator.exclusive()
+                );
+            }
+        }
+    }
+
+    let samples = &profile.samples;
+    let tagged = samples.tags.values().flatten();
+    for file in samples.files.iter().chain(&samples.training).chain(tagged) {
+        let path = samples.root.join(file);
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            bail!("sample `{}` does not exist", path.display());
+        }
+    }
+
+    println!("Pipeline:");
+    for stage in pipeline.stages() {
+        println!(
+            "  {} ({:?}) after [{}]",
+            stage.name,
+            stage.stage,
+            stage.depends_on.join(", ")
+        );
+    }
+
+    println!("Profile is valid");
+
+    Ok(())
+}
+
+/// Whether a directory looks like a sandbox created by the runner
+async fn is_sandbox(path: &Path) -> bool {
+    tokio::fs::try_exists(path.join("git"))
+        .await
+        .unwrap_or(false)
+}
@@ -1,81 +1,409 @@
-/*use std::convert::Infallible;
+use std::{net::SocketAddr, path::PathBuf};
 
-use anyhow::Context;
-use oauth2::{AuthUrl, ClientId, ClientSecret, TokenUrl, basic::BasicClient, RedirectUrl};
+use clap::{Args, Parser, Subcommand};
+use duration_string::DurationString;
 
-#[derive(Debug, clap::Parser)]
+use typster_proto::{comparison::Test, BuildVariant};
+
+use crate::profile::{BenchOptions, Profile, Samples};
+
+/// Benchmarking runner for the Typst compiler
+#[derive(Debug, Parser)]
+#[clap(name = "runner", version)]
 pub struct Config {
-    /// The GitHub OAuth2 client ID
+    /// The path to the profile file
+    #[clap(
+        long = "profile",
+        short = 'p',
+        env = "TYPSTER_PROFILE",
+        default_value = "./typster.toml",
+        global = true
+    )]
+    pub profile: PathBuf,
+
+    /// Overrides the directory in which sandboxes are created
+    #[clap(long = "workdir", env = "TYPSTER_WORKDIR", global = true)]
+    pub workdir: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+impl Config {
+    /// Loads the profile file, applying the overrides from the command line
+    pub async fn load_profile(&self) -> anyhow::Result<Profile> {
+        let mut profile = Profile::load(&self.profile).await?;
+        if let Some(workdir) = &self.workdir {
+            profile.workdir = workdir.clone();
+        }
+
+        Ok(profile)
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the pipeline on one or more commits
+    Run(RunArgs),
+
+    /// Consumes benchmark queries from the queue
+    Worker(WorkerArgs),
+
+    /// Adds a benchmark query to an embedded queue
+    Submit(SubmitArgs),
+
+    /// Compares the results of two benchmark runs
+    Compare(CompareArgs),
+
+    /// Prints a summary of the results of a benchmark run
+    Report(ReportArgs),
+
+    /// Prints the change points in the history of a branch
+    History(HistoryArgs),
+
+    /// Lists the runs recorded in the database
+    Runs(RunsArgs),
+
+    /// Serves an HTTP API to submit queries and query recorded runs
+    Serve(ServeArgs),
+
+    /// Removes sandboxes from the working directory
+    Clean(CleanArgs),
+
+    /// Inspects the profile file
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// The URL of the repository to benchmark
+    #[clap(
+        long = "repo",
+        env = "TYPSTER_REPO",
+        default_value = "https://github.com/Dherse/typst"
+    )]
+    pub repo: String,
+
+    /// The commits to benchmark, each in its own sandbox
+    #[clap(
+        long = "commit",
+        env = "TYPSTER_COMMIT",
+        value_delimiter = ',',
+        required_unless_present = "sandbox"
+    )]
+    pub commits: Vec<String>,
+
+    /// The ID of the sandbox, randomly generated if not set
+    #[clap(long = "id", conflicts_with = "sandbox")]
+    pub id: Option<String>,
+
+    /// Resumes the pipeline in an existing sandbox
+    #[clap(long = "sandbox")]
+    pub sandbox: Option<String>,
+
+    /// Runs the pipeline again starting from this stage, requires `--sandbox`
+    #[clap(long = "from", requires = "sandbox")]
+    pub from: Option<String>,
+
+    #[clap(flatten)]
+    pub bench: BenchArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct SubmitArgs {
+    /// The directory of the embedded queue
+    #[clap(long = "spool", env = "TYPSTER_SPOOL")]
+    pub spool: PathBuf,
+
+    /// The URL of the repository to benchmark
+    #[clap(
+        long = "repo",
+        env = "TYPSTER_REPO",
+        default_value = "https://github.com/Dherse/typst"
+    )]
+    pub repo: String,
+
+    /// The commit to benchmark
+    #[clap(long = "commit", env = "TYPSTER_COMMIT")]
+    pub commit: String,
+
+    /// The ID of the query, randomly generated if not set
+    #[clap(long = "id")]
+    pub id: Option<String>,
+
+    /// The sample files to benchmark, relative to the samples directory
+    #[clap(long = "samples", value_delimiter = ',')]
+    pub samples: Vec<String>,
+
+    /// The tags of the samples to benchmark
+    #[clap(long = "tag", value_delimiter = ',')]
+    pub tags: Vec<String>,
+
+    /// The benchmark settings to use, the worker's if not set
+    #[clap(long = "bench-profile")]
+    pub bench_profile: Option<String>,
+
+    /// The build variants to benchmark
+    #[clap(long = "variant", value_delimiter = ',')]
+    pub variants: Vec<BuildVariant>,
+
+    /// A commit to benchmark with the same options, to compare against
+    #[clap(long = "baseline")]
+    pub baseline: Option<String>,
+
+    /// The priority of the query, higher priorities are served first
+    #[clap(long = "priority", default_value_t = 0)]
+    pub priority: u8,
+
+    /// The branch of the commit, whose history is checked for regressions
+    #[clap(long = "branch")]
+    pub branch: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct WorkerArgs {
+    /// Takes queries from an embedded queue in this directory instead of the
+    /// AMQP broker
+    #[clap(long = "spool", env = "TYPSTER_SPOOL")]
+    pub spool: Option<PathBuf>,
+
+    /// The address of the AMQP broker
+    #[clap(
+        long = "amqp-addr",
+        env = "AMQP_ADDR",
+        default_value = "amqp://127.0.0.1:5672/%2f"
+    )]
+    pub amqp_addr: String,
+
+    /// The queue from which benchmark queries are consumed
+    #[clap(long = "queue", env = "TYPSTER_QUEUE", default_value = "bench")]
+    pub queue: String,
+
+    /// The queue to which benchmark results are published
     #[clap(
-        long = "github-client-id",
-        value_parser = parse_client_id,
-        env = "GITHUB_CLIENT_ID"
+        long = "results-queue",
+        env = "TYPSTER_RESULTS_QUEUE",
+        default_value = "results"
     )]
-    github_client_id: ClientId,
+    pub results_queue: String,
 
-    /// The GitHub OAuth2 client secret
+    /// The queue to which the progress of running queries is published
     #[clap(
-        long = "github-client-secret",
-        value_parser = parse_client_secret,
-        env = "GITHUB_CLIENT_SECRET"
+        long = "progress-queue",
+        env = "TYPSTER_PROGRESS_QUEUE",
+        default_value = "progress"
     )]
-    github_client_secret: ClientSecret,
+    pub progress_queue: String,
 
-    /// The GitHub OAuth2 authorization URL
+    /// The queue to which regressions found in the history of branches are
+    /// published
     #[clap(
-        long = "github-auth-url",
-        value_parser = parse_auth_url,
-        env = "GITHUB_AUTH_URL",
-        default_value = "https://github.com/login/oauth/authorize"
+        long = "alerts-queue",
+        env = "TYPSTER_ALERTS_QUEUE",
+        default_value = "alerts"
     )]
-    github_auth_url: AuthUrl,
+    pub alerts_queue: String,
 
-    /// The GitHub OAuth2 token URL
+    /// The queue to which queries are moved once they failed too often
     #[clap(
-        long = "github-token-url",
-        value_parser = parse_token_url,
-        env = "GITHUB_TOKEN_URL",
-        default_value = "https://github.com/login/oauth/access_token"
+        long = "dead-letter-queue",
+        env = "TYPSTER_DEAD_LETTER_QUEUE",
+        default_value = "bench.dead"
     )]
-    github_token_url: TokenUrl,
+    pub dead_letter_queue: String,
 
+    /// Declares the query queue as a priority queue with this maximum
+    /// priority, must match an existing queue
+    #[clap(long = "max-priority", env = "TYPSTER_MAX_PRIORITY")]
+    pub max_priority: Option<u8>,
+
+    /// How many times a query is attempted before it is dead-lettered
+    #[clap(
+        long = "max-attempts",
+        env = "TYPSTER_MAX_ATTEMPTS",
+        default_value_t = 3
+    )]
+    pub max_attempts: u32,
+
+    /// How long to wait before reconnecting to the broker
     #[clap(
-        long = "redirect-url",
-        value_parser = parse_redirect_url,
-        env = "REDIRECT_URL",
+        long = "reconnect-delay",
+        env = "TYPSTER_RECONNECT_DELAY",
+        default_value = "5s"
     )]
-    redirect_url: RedirectUrl,
+    pub reconnect_delay: DurationString,
+
+    #[clap(flatten)]
+    pub bench: BenchArgs,
 }
 
-impl Config {
-    /// Create a new GitHub OAuth2 client
-    pub fn github_client(&self) -> BasicClient {
-        BasicClient::new(
-            self.github_client_id.clone(),
-            Some(self.github_client_secret.clone()),
-            self.github_auth_url.clone(),
-            Some(self.github_token_url.clone()),
-        )
-        .set_redirect_uri(self.redirect_url.clone())
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// The benchmark settings to use, `main`, `other` or a custom profile
+    #[clap(
+        long = "bench-profile",
+        env = "TYPSTER_BENCH_PROFILE",
+        default_value = "other"
+    )]
+    pub bench_profile: String,
+
+    /// The maximum number of sandboxes that run at once
+    #[clap(long = "jobs", short = 'j', env = "TYPSTER_JOBS", default_value_t = 1)]
+    pub jobs: usize,
+
+    /// Overrides the directory that contains the samples
+    #[clap(long = "samples-root", env = "TYPSTER_SAMPLES_ROOT")]
+    pub samples_root: Option<PathBuf>,
+
+    /// Overrides the sample files, relative to the samples directory
+    #[clap(long = "samples", env = "TYPSTER_SAMPLES", value_delimiter = ',')]
+    pub samples: Vec<PathBuf>,
+
+    /// Only benchmarks the samples with these tags, in addition to `--samples`
+    #[clap(long = "tag", env = "TYPSTER_TAGS", value_delimiter = ',')]
+    pub tags: Vec<String>,
+
+    /// Overrides the PGO training files, relative to the samples directory
+    #[clap(long = "training", env = "TYPSTER_TRAINING", value_delimiter = ',')]
+    pub training: Vec<PathBuf>,
+
+    /// The build variants to benchmark, every variant of the pipeline if not
+    /// set
+    #[clap(long = "variant", env = "TYPSTER_VARIANTS", value_delimiter = ',')]
+    pub variants: Vec<BuildVariant>,
+}
+
+impl BenchArgs {
+    /// Returns the samples of the profile with the overrides applied
+    pub fn samples(&self, profile: &Profile) -> anyhow::Result<Samples> {
+        let mut samples = profile.samples.clone();
+        if let Some(root) = &self.samples_root {
+            samples.root = root.clone();
+        }
+
+        if !self.training.is_empty() {
+            samples.training = self.training.clone();
+        }
+
+        samples.select(&self.samples, &self.tags)
     }
+
+    /// Returns the benchmark options of the profile with the overrides
+    /// applied
+    pub fn options(&self, profile: &Profile) -> anyhow::Result<BenchOptions> {
+        Ok(BenchOptions {
+            samples: self.samples(profile)?,
+            settings: profile.profiles.get(&self.bench_profile)?.clone(),
+            variants: self.variants.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct CompareArgs {
+    /// The results directory or file, or the JSON benchmark result, of the
+    /// baseline run
+    pub baseline: PathBuf,
+
+    /// The results directory or file, or the JSON benchmark result, of the
+    /// run to compare
+    pub candidate: PathBuf,
+
+    /// The significance level of the test
+    #[clap(long, default_value = "0.05")]
+    pub alpha: f64,
+
+    /// The relative change below which differences are not reported, such
+    /// as 0.01 for 1%
+    #[clap(long = "min-change", default_value = "0.01")]
+    pub min_change: f64,
+
+    /// The test deciding whether a difference is significant, either
+    /// `mann-whitney` or `bootstrap`
+    #[clap(long, default_value = "mann-whitney")]
+    pub test: Test,
+}
+
+#[derive(Debug, Args)]
+pub struct ReportArgs {
+    /// A sandbox ID, or the path to a results directory or file
+    pub target: String,
 }
 
-fn parse_client_id(s: &str) -> Result<ClientId, Infallible> {
-    Ok(ClientId::new(s.to_string()))
+#[derive(Debug, Args)]
+pub struct HistoryArgs {
+    /// The branch whose history to analyze
+    pub branch: String,
 }
 
-fn parse_client_secret(s: &str) -> Result<ClientSecret, Infallible> {
-    Ok(ClientSecret::new(s.to_string()))
+#[derive(Debug, Args)]
+pub struct RunsArgs {
+    /// Prints the summaries of the run with this ID instead of listing runs
+    pub id: Option<i64>,
+
+    /// Only lists the runs of this query and its baseline
+    #[clap(long = "query", conflicts_with = "id")]
+    pub query: Option<String>,
+
+    /// Only lists the runs of this repository
+    #[clap(long = "repository", conflicts_with = "id")]
+    pub repository: Option<String>,
+
+    /// Only lists the runs of this commit
+    #[clap(long = "commit", conflicts_with = "id")]
+    pub commit: Option<String>,
+
+    /// Only lists the runs of this branch
+    #[clap(long = "branch", conflicts_with = "id")]
+    pub branch: Option<String>,
+
+    /// The maximum number of runs to list, most recent first
+    #[clap(long = "limit", default_value_t = 20)]
+    pub limit: u32,
 }
 
-fn parse_auth_url(s: &str) -> anyhow::Result<AuthUrl> {
-    AuthUrl::new(s.to_string()).context("failed to parse auth URL")
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// The address to listen on
+    #[clap(
+        long = "listen",
+        env = "TYPSTER_LISTEN",
+        default_value = "127.0.0.1:8080"
+    )]
+    pub listen: SocketAddr,
+
+    /// Submits queries to an embedded queue in this directory instead of the
+    /// AMQP broker
+    #[clap(long = "spool", env = "TYPSTER_SPOOL")]
+    pub spool: Option<PathBuf>,
+
+    /// The address of the AMQP broker
+    #[clap(
+        long = "amqp-addr",
+        env = "AMQP_ADDR",
+        default_value = "amqp://127.0.0.1:5672/%2f"
+    )]
+    pub amqp_addr: String,
+
+    /// The queue to which benchmark queries are submitted
+    #[clap(long = "queue", env = "TYPSTER_QUEUE", default_value = "bench")]
+    pub queue: String,
 }
 
-fn parse_token_url(s: &str) -> anyhow::Result<TokenUrl> {
-    TokenUrl::new(s.to_string()).context("failed to parse token URL")
+#[derive(Debug, Args)]
+pub struct CleanArgs {
+    /// The IDs of the sandboxes to remove
+    #[clap(required_unless_present = "all")]
+    pub ids: Vec<String>,
+
+    /// Removes every sandbox in the working directory
+    #[clap(long = "all", conflicts_with = "ids")]
+    pub all: bool,
 }
 
-fn parse_redirect_url(s: &str) -> anyhow::Result<RedirectUrl> {
-    RedirectUrl::new(s.to_string()).context("failed to parse redirect URL")
-}*/
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Checks that the profile file is valid
+    Check,
+}
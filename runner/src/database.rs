@@ -0,0 +1,867 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, ToSql, Transaction,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use typster_proto::{BenchQuery, BenchResult, Provenance, StageOutput};
+
+/// The migrations of the schema, in order. A database is at the version of
+/// the number of migrations it applied, which is kept in its `user_version`.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_initial.sql"),
+    include_str!("../migrations/0002_queries.sql"),
+];
+
+/// Selects the columns of a [`RunRecord`]
+const SELECT_RUNS: &str = "SELECT runs.id, runs.query_id, runs.role, commits.repository,
+        commits.hash, runs.branch, runs.success, runs.failed_stage, runs.failed_variant,
+        runs.recorded
+    FROM runs JOIN commits ON commits.id = runs.commit_id";
+
+/// A persistent store of every pipeline run, in an embedded SQLite database.
+///
+/// Runs are kept with their commit, the environment they ran in, the
+/// outcome of their stages and their raw measurements and summaries, such
+/// that they outlive the sandboxes that produced them. The connection is
+/// shared by every task of the process and only used from blocking threads.
+#[derive(Clone)]
+pub struct Database {
+    conn: Arc<Mutex<Connection>>,
+}
+
+/// A pipeline run recorded in the database.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunRecord {
+    pub id: i64,
+
+    /// The ID of the query the run belongs to, which the run of its baseline
+    /// shares
+    pub query_id: String,
+
+    pub role: RunRole,
+
+    pub repository: String,
+    pub commit: String,
+    pub branch: Option<String>,
+    pub success: bool,
+
+    /// The stage that failed, if the run failed
+    pub failed_stage: Option<String>,
+
+    /// The build variant of the failed stage, if it is specific to one
+    pub failed_variant: Option<String>,
+
+    /// When the run was recorded, in seconds since the Unix epoch
+    pub recorded: u64,
+}
+
+/// Which commit of a query a run benchmarked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunRole {
+    /// The commit the query was submitted for
+    Candidate,
+
+    /// The commit the query is compared against
+    Baseline,
+}
+
+/// The statistics of a metric of a sample in a recorded run, which are
+/// missing where they were not numbers.
+#[derive(Debug, Clone, Serialize)]
+pub struct SummaryRecord {
+    pub variant: String,
+    pub sample: String,
+    pub metric: String,
+    pub count: u64,
+    pub mean: Option<f64>,
+    pub median: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub std_dev: Option<f64>,
+    pub mad: Option<f64>,
+    pub p5: Option<f64>,
+    pub p95: Option<f64>,
+    pub p99: Option<f64>,
+    pub outliers: u64,
+    pub mean_low: Option<f64>,
+    pub mean_high: Option<f64>,
+    pub median_low: Option<f64>,
+    pub median_high: Option<f64>,
+}
+
+/// The raw measurements of a metric of a sample in a recorded run, without
+/// those that were not finite numbers.
+#[derive(Debug, Clone, Serialize)]
+pub struct SeriesRecord {
+    pub variant: String,
+    pub sample: String,
+    pub metric: String,
+    pub values: Vec<f64>,
+}
+
+/// Which runs to list, every filter that is set must match.
+#[derive(Debug, Clone, Default)]
+pub struct RunFilter {
+    pub query_id: Option<String>,
+    pub repository: Option<String>,
+    pub commit: Option<String>,
+    pub branch: Option<String>,
+
+    /// The maximum number of runs, most recent first
+    pub limit: Option<u32>,
+}
+
+impl Database {
+    /// Opens the database, creating it if needed, and applies the migrations
+    /// it is missing
+    pub async fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_owned();
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .context("failed to create database directory")?;
+        }
+
+        let conn = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+            let mut conn = Connection::open(&path)
+                .with_context(|| format!("failed to open database {}", path.display()))?;
+
+            // Concurrent runners share the database, so writers wait for
+            // each other instead of failing.
+            conn.busy_timeout(std::time::Duration::from_secs(30))?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.pragma_update(None, "foreign_keys", true)?;
+            migrate(&mut conn)?;
+
+            Ok(conn)
+        })
+        .await??;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Records the run of a query on a commit, replacing an earlier run of
+    /// the same query on the same commit in the same role, such as before a
+    /// retry
+    pub async fn record(
+        &self,
+        query_id: &str,
+        role: RunRole,
+        repository: &str,
+        commit: &str,
+        branch: Option<&str>,
+        result: &BenchResult,
+    ) -> anyhow::Result<()> {
+        let query_id = query_id.to_owned();
+        let repository = repository.to_owned();
+        let commit = commit.to_owned();
+        let branch = branch.map(str::to_owned);
+        let result = result.clone();
+
+        self.with(move |conn| {
+            let tx = conn.transaction()?;
+            insert_run(
+                &tx,
+                &query_id,
+                role,
+                &repository,
+                &commit,
+                branch.as_deref(),
+                &result,
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+        .context("failed to record run")
+    }
+
+    /// Adds a submitted query, returning `false` if a query with its ID was
+    /// already submitted or has runs
+    pub async fn add_query(&self, query: &BenchQuery) -> anyhow::Result<bool> {
+        let (id, repository, commit) = (query.id.clone(), query.repo.clone(), query.commit.clone());
+        self.with(move |conn| {
+            let tx = conn.transaction()?;
+            let has_runs = tx
+                .query_row(
+                    "SELECT 1 FROM runs WHERE query_id = ?1 LIMIT 1",
+                    params![id],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+
+            let added = !has_runs
+                && tx.execute(
+                    "INSERT OR IGNORE INTO queries (id, repository, hash, submitted)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![id, repository, commit, now()],
+                )? == 1;
+
+            tx.commit()?;
+            Ok(added)
+        })
+        .await
+        .context("failed to add query")
+    }
+
+    /// Removes a submitted query, such as after it could not be queued
+    pub async fn remove_query(&self, id: &str) -> anyhow::Result<()> {
+        let id = id.to_owned();
+        self.with(move |conn| {
+            conn.execute("DELETE FROM queries WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+        .context("failed to remove query")
+    }
+
+    /// Whether a query with the ID was submitted
+    pub async fn has_query(&self, id: &str) -> anyhow::Result<bool> {
+        let id = id.to_owned();
+        self.with(move |conn| {
+            let found = conn
+                .query_row("SELECT 1 FROM queries WHERE id = ?1", params![id], |_| {
+                    Ok(())
+                })
+                .optional()?;
+
+            Ok(found.is_some())
+        })
+        .await
+        .context("failed to look up query")
+    }
+
+    /// Lists the recorded runs that match a filter, most recent first
+    pub async fn runs(&self, filter: RunFilter) -> anyhow::Result<Vec<RunRecord>> {
+        self.with(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "{} WHERE (?1 IS NULL OR runs.query_id = ?1)
+                    AND (?2 IS NULL OR commits.repository = ?2)
+                    AND (?3 IS NULL OR commits.hash = ?3)
+                    AND (?4 IS NULL OR runs.branch = ?4)
+                 ORDER BY runs.recorded DESC, runs.id DESC
+                 LIMIT ?5",
+                SELECT_RUNS
+            ))?;
+
+            let limit = filter.limit.map_or(-1, i64::from);
+            let rows = stmt.query_map(
+                params![
+                    filter.query_id,
+                    filter.repository,
+                    filter.commit,
+                    filter.branch,
+                    limit
+                ],
+                run_from_row,
+            )?;
+
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+        .context("failed to list runs")
+    }
+
+    /// Returns a recorded run, if there is one with the ID
+    pub async fn run(&self, id: i64) -> anyhow::Result<Option<RunRecord>> {
+        self.with(move |conn| {
+            let run = conn
+                .query_row(
+                    &format!("{} WHERE runs.id = ?1", SELECT_RUNS),
+                    params![id],
+                    run_from_row,
+                )
+                .optional()?;
+
+            Ok(run)
+        })
+        .await
+        .context("failed to load run")
+    }
+
+    /// Returns where and how a run was produced, if there is a run with the ID
+    pub async fn provenance(&self, run: i64) -> anyhow::Result<Option<Provenance>> {
+        self.with(move |conn| {
+            let json: Option<String> = conn
+                .query_row(
+                    "SELECT environments.provenance
+                     FROM runs JOIN environments ON environments.id = runs.environment_id
+                     WHERE runs.id = ?1",
+                    params![run],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            json.map(|json| serde_json::from_str(&json).context("invalid provenance"))
+                .transpose()
+        })
+        .await
+        .context("failed to load provenance")
+    }
+
+    /// Returns the statistics of every metric of every sample of a run
+    pub async fn summaries(&self, run: i64) -> anyhow::Result<Vec<SummaryRecord>> {
+        self.with(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT variant, sample, metric, count, mean, median, min, max, std_dev, mad,
+                        p5, p95, p99, outliers, mean_low, mean_high, median_low, median_high
+                 FROM summaries WHERE run_id = ?1
+                 ORDER BY variant, sample, metric",
+            )?;
+
+            let rows = stmt.query_map(params![run], |row| {
+                Ok(SummaryRecord {
+                    variant: row.get(0)?,
+                    sample: row.get(1)?,
+                    metric: row.get(2)?,
+                    count: row.get(3)?,
+                    mean: row.get(4)?,
+                    median: row.get(5)?,
+                    min: row.get(6)?,
+                    max: row.get(7)?,
+                    std_dev: row.get(8)?,
+                    mad: row.get(9)?,
+                    p5: row.get(10)?,
+                    p95: row.get(11)?,
+                    p99: row.get(12)?,
+                    outliers: row.get(13)?,
+                    mean_low: row.get(14)?,
+                    mean_high: row.get(15)?,
+                    median_low: row.get(16)?,
+                    median_high: row.get(17)?,
+                })
+            })?;
+
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+        .context("failed to load summaries")
+    }
+
+    /// Returns the raw measurements of every metric of every sample of a run
+    pub async fn measurements(&self, run: i64) -> anyhow::Result<Vec<SeriesRecord>> {
+        self.with(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT variant, sample, metric, value FROM measurements WHERE run_id = ?1
+                 ORDER BY variant, sample, metric, position",
+            )?;
+
+            let mut series = Vec::<SeriesRecord>::new();
+            let mut rows = stmt.query(params![run])?;
+            while let Some(row) = rows.next()? {
+                let (variant, sample, metric): (String, String, String) =
+                    (row.get(0)?, row.get(1)?, row.get(2)?);
+                let value = row.get(3)?;
+
+                match series.last_mut() {
+                    Some(last)
+                        if last.variant == variant
+                            && last.sample == sample
+                            && last.metric == metric =>
+                    {
+                        last.values.push(value)
+                    }
+                    _ => series.push(SeriesRecord {
+                        variant,
+                        sample,
+                        metric,
+                        values: vec![value],
+                    }),
+                }
+            }
+
+            Ok(series)
+        })
+        .await
+        .context("failed to load measurements")
+    }
+
+    /// Runs a closure on the connection on a blocking thread
+    async fn with<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| anyhow::anyhow!("database connection poisoned"))?;
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+fn run_from_row(row: &rusqlite::Row) -> rusqlite::Result<RunRecord> {
+    Ok(RunRecord {
+        id: row.get(0)?,
+        query_id: row.get(1)?,
+        role: row.get(2)?,
+        repository: row.get(3)?,
+        commit: row.get(4)?,
+        branch: row.get(5)?,
+        success: row.get(6)?,
+        failed_stage: row.get(7)?,
+        failed_variant: row.get(8)?,
+        recorded: row.get(9)?,
+    })
+}
+
+impl RunRole {
+    /// Returns the name of the role, as it is stored
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Candidate => "candidate",
+            Self::Baseline => "baseline",
+        }
+    }
+}
+
+impl ToSql for RunRole {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for RunRole {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "candidate" => Ok(Self::Candidate),
+            "baseline" => Ok(Self::Baseline),
+            role => Err(FromSqlError::Other(
+                format!("unknown run role `{}`", role).into(),
+            )),
+        }
+    }
+}
+
+/// Applies the migrations the database is missing, each in a transaction
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "database is at version {}, newer than the supported {}",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("failed to apply migration {}", i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        tracing::info!("migrated database to version {}", i + 1);
+    }
+
+    Ok(())
+}
+
+fn insert_run(
+    tx: &Transaction,
+    query_id: &str,
+    role: RunRole,
+    repository: &str,
+    commit: &str,
+    branch: Option<&str>,
+    result: &BenchResult,
+) -> anyhow::Result<()> {
+    let (provenance, clone, fetch) = match result {
+        BenchResult::Success {
+            provenance,
+            clone,
+            fetch,
+            ..
+        } => (provenance, Some(clone), Some(fetch)),
+        BenchResult::Failure {
+            provenance,
+            clone,
+            fetch,
+            ..
+        } => (provenance, clone.as_ref(), fetch.as_ref()),
+    };
+
+    let (failed_stage, failed_variant) = match result {
+        BenchResult::Success { .. } => (None, None),
+        BenchResult::Failure { stage, variant, .. } => (
+            Some(stage.to_string()),
+            variant.map(|variant| variant.to_string()),
+        ),
+    };
+
+    tx.execute(
+        "INSERT OR IGNORE INTO commits (repository, hash) VALUES (?1, ?2)",
+        params![repository, commit],
+    )?;
+    let commit_id: i64 = tx.query_row(
+        "SELECT id FROM commits WHERE repository = ?1 AND hash = ?2",
+        params![repository, commit],
+        |row| row.get(0),
+    )?;
+
+    let environment_id = insert_environment(tx, provenance)?;
+    let recorded = now();
+
+    // A run that is recorded again, such as after a retry, replaces its
+    // stages and measurements through the cascade.
+    tx.execute(
+        "DELETE FROM runs WHERE query_id = ?1 AND commit_id = ?2 AND role = ?3",
+        params![query_id, commit_id, role],
+    )?;
+    tx.execute(
+        "INSERT INTO runs (query_id, role, commit_id, environment_id, branch, success,
+                           failed_stage, failed_variant, recorded)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            query_id,
+            role,
+            commit_id,
+            environment_id,
+            branch,
+            failed_stage.is_none(),
+            failed_stage,
+            failed_variant,
+            recorded,
+        ],
+    )?;
+    let id = tx.last_insert_rowid();
+
+    let mut stages = tx.prepare(
+        "INSERT INTO stages (run_id, name, variant, outcome, exitcode, wall_time, cpu_time,
+                             peak_memory)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    let mut insert_stage = |name: &str, variant: Option<String>, output: &StageOutput| {
+        stages.execute(params![
+            id,
+            name,
+            variant,
+            output.outcome.to_string(),
+            output.exitcode,
+            output.resources.wall_time.as_secs_f64(),
+            output.resources.cpu_time.as_secs_f64(),
+            output.resources.peak_memory,
+        ])
+    };
+
+    for (name, output) in [("clone", clone), ("fetch", fetch)] {
+        if let Some(output) = output {
+            insert_stage(name, None, output)?;
+        }
+    }
+
+    let mut measurements = tx.prepare(
+        "INSERT INTO measurements (run_id, variant, sample, metric, position, value)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for variant in result.variants() {
+        for (name, output) in &variant.stages {
+            insert_stage(name, Some(variant.variant.to_string()), output)?;
+        }
+
+        for (sample, metric, values) in variant.series() {
+            // SQLite stores NaN as null, and infinities are no measurements.
+            for (position, value) in values.iter().enumerate() {
+                if !value.is_finite() {
+                    continue;
+                }
+
+                measurements.execute(params![
+                    id,
+                    variant.variant.to_string(),
+                    sample,
+                    metric.to_string(),
+                    position,
+                    value,
+                ])?;
+            }
+        }
+    }
+
+    let mut summaries = tx.prepare(
+        "INSERT INTO summaries (run_id, variant, sample, metric, count, mean, median, min, max,
+                                std_dev, mad, p5, p95, p99, outliers, mean_low, mean_high,
+                                median_low, median_high)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                 ?18, ?19)",
+    )?;
+    let finite = |value: f64| Some(value).filter(|value| value.is_finite());
+    for summary in result.summaries() {
+        let stats = &summary.summary;
+        summaries.execute(params![
+            id,
+            summary.variant.to_string(),
+            summary.sample,
+            summary.metric.to_string(),
+            stats.count,
+            finite(stats.mean),
+            finite(stats.median),
+            finite(stats.min),
+            finite(stats.max),
+            finite(stats.std_dev),
+            finite(stats.mad),
+            finite(stats.p5),
+            finite(stats.p95),
+            finite(stats.p99),
+            stats.outliers.total(),
+            finite(stats.mean_ci.low),
+            finite(stats.mean_ci.high),
+            finite(stats.median_ci.low),
+            finite(stats.median_ci.high),
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Returns the current time in seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Returns the ID of the environment of a provenance, adding it if it is new
+fn insert_environment(tx: &Transaction, provenance: &Provenance) -> anyhow::Result<i64> {
+    let json = serde_json::to_string(provenance).context("failed to serialize provenance")?;
+    let hash = format!("{:x}", Sha256::digest(json.as_bytes()));
+
+    tx.execute(
+        "INSERT OR IGNORE INTO environments (hash, cpu_model, cores, kernel, docker, rustc,
+                                             runner, profile_hash, provenance)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            hash,
+            provenance.cpu_model,
+            provenance.cores,
+            provenance.kernel,
+            provenance.docker,
+            provenance.rustc,
+            provenance.runner,
+            provenance.profile_hash,
+            json,
+        ],
+    )?;
+
+    Ok(tx.query_row(
+        "SELECT id FROM environments WHERE hash = ?1",
+        params![hash],
+        |row| row.get(0),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use typster_proto::{
+        BenchWalltimeSamples, BuildVariant, SampleMetric, SampleSummary, Summary, VariantResult,
+    };
+
+    use super::*;
+
+    fn database() -> Database {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "foreign_keys", true).unwrap();
+        migrate(&mut conn).unwrap();
+
+        Database {
+            conn: Arc::new(Mutex::new(conn)),
+        }
+    }
+
+    /// Returns a successful result with walltimes of one sample
+    fn result(walltime: &[f64]) -> BenchResult {
+        let variant = VariantResult {
+            variant: BuildVariant::Plain,
+            samples: Vec::new(),
+            walltimes: vec![BenchWalltimeSamples {
+                name: "sample.typ".to_owned(),
+                walltime: walltime.to_vec(),
+            }],
+            stages: BTreeMap::new(),
+        };
+
+        let mut summary = Summary::of(&[1.0, 2.0, 3.0]).unwrap();
+        summary.mean = f64::NAN;
+
+        BenchResult::Success {
+            id: "query".to_owned(),
+            samples: Vec::new(),
+            walltimes: Vec::new(),
+            clone: StageOutput::default(),
+            fetch: StageOutput::default(),
+            build: StageOutput::default(),
+            bench_e2e: StageOutput::default(),
+            bench_walltime: StageOutput::default(),
+            variants: vec![variant],
+            provenance: Provenance::default(),
+            summaries: vec![SampleSummary {
+                variant: BuildVariant::Plain,
+                sample: "sample.typ".to_owned(),
+                metric: SampleMetric::Walltime,
+                summary,
+            }],
+        }
+    }
+
+    async fn record(database: &Database, query: &str, role: RunRole, commit: &str) -> i64 {
+        database
+            .record(query, role, "typst", commit, Some("main"), &result(&[1.0]))
+            .await
+            .unwrap();
+
+        let filter = RunFilter {
+            query_id: Some(query.to_owned()),
+            commit: Some(commit.to_owned()),
+            ..Default::default()
+        };
+
+        let runs = database.runs(filter).await.unwrap();
+        runs.iter().find(|run| run.role == role).unwrap().id
+    }
+
+    async fn count(database: &Database) -> usize {
+        database.runs(RunFilter::default()).await.unwrap().len()
+    }
+
+    #[test]
+    fn migrate_applies_every_migration_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+
+    #[tokio::test]
+    async fn record_replaces_only_the_same_query_commit_and_role() {
+        let database = database();
+
+        // A query whose baseline is its own commit keeps both runs.
+        let candidate = record(&database, "q1", RunRole::Candidate, "a").await;
+        let baseline = record(&database, "q1", RunRole::Baseline, "a").await;
+        assert_ne!(candidate, baseline);
+        assert_eq!(count(&database).await, 2);
+
+        // A retry replaces the run together with its measurements.
+        let retried = record(&database, "q1", RunRole::Candidate, "a").await;
+        assert_ne!(retried, candidate);
+        assert_eq!(count(&database).await, 2);
+        assert!(database.run(candidate).await.unwrap().is_none());
+        assert!(database.measurements(candidate).await.unwrap().is_empty());
+        assert!(database.run(baseline).await.unwrap().is_some());
+
+        record(&database, "q2", RunRole::Candidate, "a").await;
+        record(&database, "q1", RunRole::Candidate, "b").await;
+        assert_eq!(count(&database).await, 4);
+    }
+
+    #[tokio::test]
+    async fn record_keeps_non_finite_values_out() {
+        let database = database();
+        database
+            .record(
+                "q1",
+                RunRole::Candidate,
+                "typst",
+                "a",
+                None,
+                &result(&[1.0, f64::NAN, 3.0, f64::INFINITY]),
+            )
+            .await
+            .unwrap();
+
+        let run = database.runs(RunFilter::default()).await.unwrap()[0].id;
+        let series = database.measurements(run).await.unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].metric, "walltime");
+        assert_eq!(series[0].values, [1.0, 3.0]);
+
+        let summaries = database.summaries(run).await.unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].mean, None);
+        assert_eq!(summaries[0].median, Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn runs_match_every_filter() {
+        let database = database();
+        let result = result(&[1.0]);
+        for (query, repository, commit, branch) in [
+            ("q1", "typst", "a", Some("main")),
+            ("q2", "typst", "b", None),
+            ("q3", "fork", "a", Some("main")),
+        ] {
+            database
+                .record(
+                    query,
+                    RunRole::Candidate,
+                    repository,
+                    commit,
+                    branch,
+                    &result,
+                )
+                .await
+                .unwrap();
+        }
+
+        let queries = |filter: RunFilter| {
+            let database = database.clone();
+            async move {
+                let runs = database.runs(filter).await.unwrap();
+                runs.into_iter().map(|run| run.query_id).collect::<Vec<_>>()
+            }
+        };
+
+        // The most recently recorded run comes first.
+        assert_eq!(queries(RunFilter::default()).await, ["q3", "q2", "q1"]);
+        let filter = RunFilter {
+            repository: Some("typst".to_owned()),
+            commit: Some("a".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(queries(filter).await, ["q1"]);
+
+        let filter = RunFilter {
+            branch: Some("main".to_owned()),
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(queries(filter).await, ["q3"]);
+
+        let filter = RunFilter {
+            query_id: Some("q2".to_owned()),
+            ..Default::default()
+        };
+        let runs = database.runs(filter).await.unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].repository, "typst");
+        assert_eq!(runs[0].commit, "b");
+        assert_eq!(runs[0].role, RunRole::Candidate);
+        assert!(runs[0].branch.is_none() && runs[0].success);
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use typster_proto::{
+    changepoint::{self, ChangePoint},
+    BenchResult, BuildVariant, RegressionAlert, SampleMetric,
+};
+
+use crate::{lock::FileLock, profile::HistorySettings, provenance::environment, runs::random_id};
+
+/// The prefix of the line the clone container prints with the committer time
+/// of the commit
+pub const COMMITTED_MARKER: &str = "typster-committed ";
+
+/// The results of the commits of branches, kept to find regressions that
+/// creep in across many commits.
+///
+/// Every branch has a JSON file holding the median of every metric of every
+/// sample of its commits, ordered by committer time. Results of different
+/// environments form separate series. Writers hold a lock on the file of the
+/// branch, such that concurrent workers can share a store.
+pub struct HistoryStore {
+    root: PathBuf,
+    settings: HistorySettings,
+}
+
+/// The history of a branch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct History {
+    /// The name of the branch
+    pub branch: String,
+
+    /// The results of the commits, ordered by committer time
+    pub runs: Vec<HistoryRun>,
+
+    /// The change points whose alerts were published, by series and commit
+    #[serde(default)]
+    pub alerted: BTreeSet<(SeriesKey, String)>,
+}
+
+/// The result of a commit in the history of a branch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRun {
+    /// The ID of the query that benchmarked the commit
+    pub id: String,
+
+    /// The commit hash
+    pub commit: String,
+
+    /// When the result was recorded, in seconds since the Unix epoch
+    pub recorded: u64,
+
+    /// The committer time of the commit, in seconds since the Unix epoch,
+    /// which orders the runs of a branch
+    #[serde(default)]
+    pub committed: u64,
+
+    /// The environment the commit was benchmarked in
+    #[serde(default)]
+    pub environment: String,
+
+    /// The median of every metric of every sample
+    pub medians: Vec<HistoryPoint>,
+}
+
+/// The median of a metric of a sample in a run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryPoint {
+    pub variant: BuildVariant,
+    pub sample: String,
+    pub metric: SampleMetric,
+    pub median: f64,
+}
+
+/// A metric of a sample of a build variant in an environment, which forms a
+/// series across the commits of a branch.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SeriesKey {
+    #[serde(default)]
+    pub environment: String,
+    pub variant: BuildVariant,
+    pub sample: String,
+    pub metric: SampleMetric,
+}
+
+/// A change point in the series of a branch, located at a commit.
+#[derive(Debug, Clone)]
+pub struct SeriesChange {
+    pub key: SeriesKey,
+
+    /// The first run after the change
+    pub run: HistoryRun,
+
+    pub change: ChangePoint,
+}
+
+impl HistoryStore {
+    /// Opens the store, creating its directory if needed
+    pub async fn open(settings: &HistorySettings) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&settings.root)
+            .await
+            .context("failed to create history directory")?;
+
+        Ok(Self {
+            root: settings.root.clone(),
+            settings: settings.clone(),
+        })
+    }
+
+    /// Adds the successful result of a commit to the history of a branch,
+    /// replacing an earlier result of the same commit in the same
+    /// environment, and returns the regressions that were not reported yet
+    ///
+    /// The regressions are only marked as reported by [`Self::mark_alerted`],
+    /// such that those whose alerts could not be published are returned
+    /// again after the next commit.
+    pub async fn record(
+        &self,
+        branch: &str,
+        id: &str,
+        commit: &str,
+        result: &BenchResult,
+    ) -> anyhow::Result<Vec<SeriesChange>> {
+        let BenchResult::Success {
+            clone, provenance, ..
+        } = result
+        else {
+            bail!("only successful results are added to the history");
+        };
+
+        let Some(committed) = committed_time(&clone.stdout) else {
+            bail!("the clone of {} did not report its committer time", commit);
+        };
+
+        let path = self.path(branch);
+        let _lock = FileLock::exclusive(path.with_extension("lock")).await?;
+
+        let mut history = self.load(branch).await?;
+        let run = HistoryRun {
+            id: id.to_owned(),
+            commit: commit.to_owned(),
+            recorded: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            committed,
+            environment: environment(provenance),
+            medians: result
+                .summaries()
+                .iter()
+                .map(|summary| HistoryPoint {
+                    variant: summary.variant,
+                    sample: summary.sample.clone(),
+                    metric: summary.metric,
+                    median: summary.summary.median,
+                })
+                .collect(),
+        };
+
+        match history
+            .runs
+            .iter_mut()
+            .find(|existing| existing.commit == commit && existing.environment == run.environment)
+        {
+            Some(existing) => *existing = run,
+            None => history.runs.push(run),
+        }
+
+        // Commits may be benchmarked in any order, results recorded at the
+        // same committer time keep the order they were recorded in.
+        history
+            .runs
+            .sort_by_key(|run| (run.committed, run.recorded));
+
+        self.save(&path, &history).await?;
+        Ok(history.unreported(&self.settings))
+    }
+
+    /// Marks regressions of a branch as reported, once their alerts were
+    /// published
+    pub async fn mark_alerted(&self, branch: &str, changes: &[SeriesChange]) -> anyhow::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let path = self.path(branch);
+        let _lock = FileLock::exclusive(path.with_extension("lock")).await?;
+
+        let mut history = self.load(branch).await?;
+        for change in changes {
+            history
+                .alerted
+                .insert((change.key.clone(), change.run.commit.clone()));
+        }
+
+        self.save(&path, &history).await
+    }
+
+    /// Loads the history of a branch, which is empty if nothing was recorded
+    pub async fn load(&self, branch: &str) -> anyhow::Result<History> {
+        let path = self.path(branch);
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Ok(History {
+                branch: branch.to_owned(),
+                runs: Vec::new(),
+                alerted: BTreeSet::new(),
+            });
+        }
+
+        let data = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?;
+
+        serde_json::from_slice(&data).with_context(|| format!("invalid history {}", path.display()))
+    }
+
+    /// Returns the file of a branch, named by a hash as branch names may
+    /// contain slashes
+    fn path(&self, branch: &str) -> PathBuf {
+        let hash = format!("{:x}", Sha256::digest(branch.as_bytes()));
+        self.root.join(format!("{}.json", &hash[..16]))
+    }
+
+    /// Replaces the file of a history, which must be locked
+    async fn save(&self, path: &Path, history: &History) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(history).context("failed to serialize history")?;
+        let tmp = self.root.join(format!(".tmp-{}", random_id(8)));
+        tokio::fs::write(&tmp, data)
+            .await
+            .context("failed to write history")?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .context("failed to write history")
+    }
+}
+
+impl History {
+    /// Returns the series of every metric, as the runs that measured it and
+    /// the median they measured
+    pub fn series(&self) -> BTreeMap<SeriesKey, Vec<(&HistoryRun, f64)>> {
+        let mut series = BTreeMap::<_, Vec<_>>::new();
+        for run in &self.runs {
+            for point in &run.medians {
+                let key = SeriesKey {
+                    environment: run.environment.clone(),
+                    variant: point.variant,
+                    sample: point.sample.clone(),
+                    metric: point.metric,
+                };
+                series.entry(key).or_default().push((run, point.median));
+            }
+        }
+
+        series
+    }
+
+    /// Returns every significant change point of every series
+    pub fn changes(&self, settings: &HistorySettings) -> Vec<SeriesChange> {
+        let mut changes = Vec::new();
+        for (key, points) in self.series() {
+            let values = points.iter().map(|(_, median)| *median).collect::<Vec<_>>();
+            for change in changepoint::detect(&values, &settings.detection) {
+                changes.push(SeriesChange {
+                    key: key.clone(),
+                    run: points[change.index].0.clone(),
+                    change,
+                });
+            }
+        }
+
+        changes
+    }
+
+    /// Returns the change points at which a series got slower by at least
+    /// the minimum change
+    pub fn regressions(&self, settings: &HistorySettings) -> Vec<SeriesChange> {
+        self.changes(settings)
+            .into_iter()
+            .filter(|change| change.change.change() >= settings.min_change)
+            .collect()
+    }
+
+    /// Returns the regressions that were not reported yet
+    ///
+    /// Change points drift as commits are added around them, so a regression
+    /// counts as reported if one was reported in its series within the alert
+    /// window of runs around it.
+    pub fn unreported(&self, settings: &HistorySettings) -> Vec<SeriesChange> {
+        let series = self.series();
+        self.regressions(settings)
+            .into_iter()
+            .filter(|regression| {
+                let Some(points) = series.get(&regression.key) else {
+                    return true;
+                };
+
+                !points.iter().enumerate().any(|(index, (run, _))| {
+                    index.abs_diff(regression.change.index) <= settings.alert_window
+                        && self
+                            .alerted
+                            .contains(&(regression.key.clone(), run.commit.clone()))
+                })
+            })
+            .collect()
+    }
+}
+
+impl SeriesChange {
+    /// Returns the alert reporting the change as a regression of a branch
+    pub fn to_alert(&self, branch: &str) -> RegressionAlert {
+        RegressionAlert {
+            branch: branch.to_owned(),
+            variant: self.key.variant,
+            sample: self.key.sample.clone(),
+            metric: self.key.metric,
+            commit: self.run.commit.clone(),
+            id: self.run.id.clone(),
+            before: self.change.before,
+            after: self.change.after,
+            change: self.change.change(),
+            confidence: self.change.confidence(),
+        }
+    }
+}
+
+/// Returns the committer time the clone container printed, if any
+pub fn committed_time(stdout: &[String]) -> Option<u64> {
+    stdout
+        .iter()
+        .find_map(|line| line.strip_prefix(COMMITTED_MARKER))
+        .and_then(|time| time.trim().parse().ok())
+}
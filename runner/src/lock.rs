@@ -0,0 +1,57 @@
+use std::{fs::File, path::Path};
+
+use anyhow::Context;
+use fs2::FileExt;
+
+/// An advisory lock on a file, shared with every process on the host.
+///
+/// The lock is released when the guard is dropped.
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Waits until the exclusive lock on the file is acquired, creating the
+    /// file if needed
+    pub async fn exclusive(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_owned();
+        tokio::task::spawn_blocking(move || {
+            let file = open(&path)?;
+            FileExt::lock_exclusive(&file)
+                .with_context(|| format!("failed to lock {}", path.display()))?;
+
+            Ok(Self { file })
+        })
+        .await
+        .context("failed to join lock task")?
+    }
+
+    /// Acquires the exclusive lock on the file if nobody else holds it,
+    /// creating the file if needed
+    pub fn try_exclusive(path: impl AsRef<Path>) -> anyhow::Result<Option<Self>> {
+        let path = path.as_ref();
+        let file = open(path)?;
+        match FileExt::try_lock_exclusive(&file) {
+            Ok(()) => Ok(Some(Self { file })),
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("failed to lock {}", path.display())),
+        }
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<File> {
+    File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("failed to open lock file {}", path.display()))
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Err(err) = FileExt::unlock(&self.file) {
+            tracing::error!("failed to release file lock: {}", err);
+        }
+    }
+}
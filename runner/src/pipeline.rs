@@ -0,0 +1,866 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::{bail, Context};
+use bollard::Docker;
+use bytesize::ByteSize;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use typster_proto::{BuildVariant, ProgressEvent, Stage, StageOutcome};
+
+use crate::{
+    artifacts::{ArtifactKey, ArtifactStore},
+    profile::{BenchOptions, PipelineStage, Profile, Samples},
+    provenance::{rustc_version, RUSTC_MARKER},
+    sandbox::{ContainerOutput, Sandbox},
+};
+
+/// A validated pipeline, with its stages in execution order.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    stages: Vec<PipelineStage>,
+    artifacts: Option<ArtifactStore>,
+}
+
+/// The outcome of executing a pipeline on a sandbox.
+#[derive(Debug, Default)]
+pub struct PipelineReport {
+    /// The output of every stage that ran, in execution order
+    pub outputs: Vec<StageReport>,
+
+    /// The name of the stage that failed, if any
+    pub failed: Option<String>,
+}
+
+/// The output of a single stage of a pipeline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageReport {
+    /// The name of the stage in the pipeline
+    pub name: String,
+
+    /// The kind of the stage
+    pub kind: Stage,
+
+    /// The build variant the stage builds or benchmarks, if it is specific
+    /// to one
+    pub variant: Option<BuildVariant>,
+
+    /// The output of the stage's container
+    pub output: ContainerOutput,
+}
+
+/// The persisted progress of a pipeline in a sandbox, used to resume it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineState {
+    /// The repository URL of the sandbox
+    pub repository: String,
+
+    /// The commit hash of the sandbox
+    pub commit: String,
+
+    /// The hash of the benchmark options the completed stages ran with
+    pub options: String,
+
+    /// The stages that completed successfully, in execution order
+    pub completed: Vec<StageReport>,
+}
+
+impl PipelineState {
+    /// Loads the state of a sandbox, or creates an empty one if the
+    /// pipeline has never run in it. Fails if the sandbox was created for
+    /// another commit, whose completed stages must not be reused.
+    pub async fn load(sandbox: &Sandbox) -> anyhow::Result<Self> {
+        match Self::read(sandbox.state_file()).await? {
+            Some(state)
+                if state.repository != sandbox.repository || state.commit != sandbox.commit =>
+            {
+                bail!(
+                    "sandbox `{}` belongs to commit {} of {}, not {} of {}",
+                    sandbox.id,
+                    state.commit,
+                    state.repository,
+                    sandbox.commit,
+                    sandbox.repository
+                );
+            }
+            Some(state) => Ok(state),
+            None => Ok(Self {
+                repository: sandbox.repository.clone(),
+                commit: sandbox.commit.clone(),
+                options: String::new(),
+                completed: Vec::new(),
+            }),
+        }
+    }
+
+    /// Reads a state file, returning `None` if it does not exist
+    pub async fn read(path: impl AsRef<Path>) -> anyhow::Result<Option<Self>> {
+        let path = path.as_ref();
+        if !tokio::fs::try_exists(path).await.unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let file = tokio::fs::read(path)
+            .await
+            .context("failed to read pipeline state")?;
+
+        serde_json::from_slice(&file)
+            .context("failed to parse pipeline state")
+            .map(Some)
+    }
+
+    /// Writes the state into the sandbox
+    pub async fn save(&self, sandbox: &Sandbox) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(self).context("failed to serialize pipeline state")?;
+
+        // Write to a temporary file first so that a crash never leaves a
+        // truncated state behind.
+        let path = sandbox.state_file();
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, data)
+            .await
+            .context("failed to write pipeline state")?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .context("failed to write pipeline state")
+    }
+
+    /// Returns the report of a completed stage
+    pub fn completed(&self, name: &str) -> Option<&StageReport> {
+        self.completed.iter().find(|stage| stage.name == name)
+    }
+}
+
+impl PipelineReport {
+    /// Returns the output of a stage by its name
+    pub fn output(&self, name: &str) -> Option<&ContainerOutput> {
+        self.outputs
+            .iter()
+            .find(|stage| stage.name == name)
+            .map(|stage| &stage.output)
+    }
+
+    /// Returns the output of the last stage of a given kind that ran
+    pub fn output_of(&self, kind: Stage) -> Option<&ContainerOutput> {
+        self.outputs
+            .iter()
+            .rev()
+            .find(|stage| stage.kind == kind)
+            .map(|stage| &stage.output)
+    }
+
+    /// Returns the stages that built or benchmarked a build variant
+    pub fn outputs_of_variant(
+        &self,
+        variant: BuildVariant,
+    ) -> impl Iterator<Item = &StageReport> + '_ {
+        self.outputs
+            .iter()
+            .filter(move |stage| stage.variant == Some(variant))
+    }
+
+    /// Returns the report of the stage that failed, if any
+    pub fn failed_stage(&self) -> Option<&StageReport> {
+        let failed = self.failed.as_ref()?;
+        self.outputs.iter().find(|stage| &stage.name == failed)
+    }
+
+    /// Returns the kind of the stage that failed, if any
+    pub fn failed_kind(&self) -> Option<Stage> {
+        self.failed_stage().map(|stage| stage.kind)
+    }
+
+    /// Whether every stage of the pipeline succeeded
+    pub fn is_success(&self) -> bool {
+        self.failed.is_none()
+    }
+}
+
+impl Pipeline {
+    /// Validates the stages and orders them such that every stage runs
+    /// after its dependencies. Stages without ordering constraints
+    /// between them keep their declaration order.
+    pub fn new(stages: &[PipelineStage]) -> anyhow::Result<Self> {
+        let mut names = HashSet::new();
+        for stage in stages {
+            if !names.insert(stage.name.as_str()) {
+                bail!("duplicate pipeline stage `{}`", stage.name);
+            }
+        }
+
+        for stage in stages {
+            if stage.cache.is_some() && !stage.stage.is_build() {
+                bail!(
+                    "pipeline stage `{}` caches artifacts but does not build",
+                    stage.name
+                );
+            }
+
+            let mut dependencies = HashSet::new();
+            for dependency in &stage.depends_on {
+                if !names.contains(dependency.as_str()) {
+                    bail!(
+                        "pipeline stage `{}` depends on unknown stage `{}`",
+                        stage.name,
+                        dependency
+                    );
+                }
+
+                if !dependencies.insert(dependency.as_str()) {
+                    bail!(
+                        "pipeline stage `{}` depends on `{}` more than once",
+                        stage.name,
+                        dependency
+                    );
+                }
+            }
+        }
+
+        let mut remaining: HashMap<&str, usize> = stages
+            .iter()
+            .map(|stage| (stage.name.as_str(), stage.depends_on.len()))
+            .collect();
+
+        let mut ordered = Vec::with_capacity(stages.len());
+        while ordered.len() < stages.len() {
+            let next = stages
+                .iter()
+                .find(|stage| remaining.get(stage.name.as_str()) == Some(&0))
+                .with_context(|| {
+                    let cycle = find_cycle(stages, &remaining);
+                    format!("pipeline contains a cycle: {}", cycle.join(" -> "))
+                })?;
+
+            remaining.remove(next.name.as_str());
+            for stage in stages {
+                if stage.depends_on.contains(&next.name) {
+                    if let Some(count) = remaining.get_mut(stage.name.as_str()) {
+                        *count -= 1;
+                    }
+                }
+            }
+
+            ordered.push(next.clone());
+        }
+
+        Ok(Self {
+            stages: ordered,
+            artifacts: None,
+        })
+    }
+
+    /// Builds the pipeline of a profile, using the caches it configures
+    pub async fn from_profile(profile: &Profile) -> anyhow::Result<Self> {
+        let artifacts = match &profile.cache {
+            Some(cache) => Some(ArtifactStore::open(cache).await?),
+            None => None,
+        };
+
+        Ok(Self::new(&profile.pipeline)?.with_artifacts(artifacts))
+    }
+
+    /// Uses an artifact store to skip the builds of already built commits
+    pub fn with_artifacts(mut self, artifacts: Option<ArtifactStore>) -> Self {
+        self.artifacts = artifacts;
+        self
+    }
+
+    /// Returns the stages in execution order
+    pub fn stages(&self) -> &[PipelineStage] {
+        &self.stages
+    }
+
+    /// Marks a stage and every stage that transitively depends on it as not
+    /// completed, such that they run again on the next execution.
+    pub fn invalidate_from(&self, state: &mut PipelineState, name: &str) -> anyhow::Result<()> {
+        if !self.stages.iter().any(|stage| stage.name == name) {
+            bail!("unknown pipeline stage `{}`", name);
+        }
+
+        self.invalidate(state, HashSet::from([name]));
+        Ok(())
+    }
+
+    /// Marks stages as not completed, together with every stage that
+    /// transitively depends on them and the builds of the binaries they run.
+    ///
+    /// Every build writes the same binary, so a stage that runs again would
+    /// otherwise run whichever binary was built last.
+    fn invalidate<'a>(&'a self, state: &mut PipelineState, mut invalidated: HashSet<&'a str>) {
+        loop {
+            let count = invalidated.len();
+
+            // Stages are in execution order, so dependents always come after
+            // their dependencies.
+            for stage in &self.stages {
+                if stage
+                    .depends_on
+                    .iter()
+                    .any(|dependency| invalidated.contains(dependency.as_str()))
+                {
+                    invalidated.insert(stage.name.as_str());
+                }
+            }
+
+            let builds = self
+                .stages
+                .iter()
+                .filter(|stage| invalidated.contains(stage.name.as_str()))
+                .filter_map(|stage| self.binary_build(stage))
+                .collect::<Vec<_>>();
+            invalidated.extend(builds);
+
+            if invalidated.len() == count {
+                break;
+            }
+        }
+
+        state
+            .completed
+            .retain(|stage| !invalidated.contains(stage.name.as_str()));
+    }
+
+    /// Returns the nearest build a stage depends on, whose binary it runs, if
+    /// it runs one
+    fn binary_build(&self, stage: &PipelineStage) -> Option<&str> {
+        if !runs_binary(stage.stage) {
+            return None;
+        }
+
+        let mut queue = stage
+            .depends_on
+            .iter()
+            .map(String::as_str)
+            .collect::<VecDeque<_>>();
+        let mut seen = HashSet::new();
+        while let Some(name) = queue.pop_front() {
+            if !seen.insert(name) {
+                continue;
+            }
+
+            let dependency = self.stages.iter().find(|stage| stage.name == name)?;
+            if builds_binary(dependency.stage) {
+                return Some(&dependency.name);
+            }
+
+            queue.extend(dependency.depends_on.iter().map(String::as_str));
+        }
+
+        None
+    }
+
+    /// Executes every stage of the pipeline on the sandbox, stopping at the
+    /// first stage that does not meet its success criteria.
+    ///
+    /// Stages that already completed in a previous execution are skipped,
+    /// unless one of their dependencies ran again.
+    pub async fn execute(
+        &self,
+        sandbox: &Sandbox,
+        profile: &Profile,
+        docker: &Docker,
+        options: &BenchOptions,
+    ) -> anyhow::Result<PipelineReport> {
+        let mut report = PipelineReport::default();
+        let mut state = PipelineState::load(sandbox).await?;
+        let mut ran = HashSet::new();
+
+        let hash = options.hash();
+        if state.options != hash {
+            if !state.completed.is_empty() {
+                tracing::info!("benchmark options changed, running the benchmarks again");
+            }
+
+            let benchmarks = self
+                .stages
+                .iter()
+                .filter(|stage| runs_binary(stage.stage))
+                .map(|stage| stage.name.as_str())
+                .collect();
+            self.invalidate(&mut state, benchmarks);
+            state.options = hash;
+        }
+
+        let keys = self
+            .artifact_keys(sandbox, profile, docker, &options.samples)
+            .await?;
+        let mut hits = HashSet::new();
+        if let Some(artifacts) = &self.artifacts {
+            for (name, key) in &keys {
+                if artifacts.contains(key).await {
+                    hits.insert(*name);
+                }
+            }
+        }
+
+        let needed = self.needed_stages(&hits, options);
+
+        for stage in &self.stages {
+            if !options.runs_variant(stage.variant()) {
+                tracing::info!(
+                    "stage `{}` is of an unselected variant, skipping",
+                    stage.name
+                );
+                continue;
+            }
+
+            if !needed.contains(stage.name.as_str()) {
+                tracing::info!("stage `{}` only feeds cached builds, skipping", stage.name);
+                continue;
+            }
+
+            let dependency_ran = stage
+                .depends_on
+                .iter()
+                .any(|dependency| ran.contains(dependency.as_str()));
+
+            if let Some(completed) = state.completed(&stage.name).filter(|_| !dependency_ran) {
+                tracing::info!("stage `{}` already completed, skipping", stage.name);
+                report.outputs.push(completed.clone());
+                continue;
+            }
+
+            ran.insert(stage.name.as_str());
+            state
+                .completed
+                .retain(|completed| completed.name != stage.name);
+
+            sandbox.report(ProgressEvent::StageStarted {
+                stage: stage.name.clone(),
+            });
+
+            let started = Instant::now();
+            let key = keys.get(stage.name.as_str());
+            let output = match (&self.artifacts, key) {
+                (Some(artifacts), Some(key)) if hits.contains(stage.name.as_str()) => {
+                    if !artifacts.restore(key, sandbox).await? {
+                        bail!(
+                            "artifacts of stage `{}` were evicted during the run",
+                            stage.name
+                        );
+                    }
+
+                    tracing::info!("restored artifacts of stage `{}` from cache", stage.name);
+                    let mut stdout = vec![format!("restored artifacts {} from cache", key.hash())];
+                    if let Some(rustc) = artifacts.rustc(key).await? {
+                        stdout.push(format!("{}{}", RUSTC_MARKER, rustc));
+                    }
+
+                    ContainerOutput {
+                        stdout,
+                        stderr: Vec::new(),
+                        exitcode: 0,
+                        outcome: StageOutcome::Success,
+                        resources: Default::default(),
+                        cpuset: None,
+                    }
+                }
+                _ => {
+                    let span = tracing::info_span!("stage", name = %stage.name);
+                    run_stage(stage, sandbox, profile, docker, options)
+                        .instrument(span)
+                        .await
+                        .unwrap_or_else(|err| ContainerOutput::infra_error(&err))
+                }
+            };
+            let success = check_success(stage, sandbox, &output).await;
+            sandbox.report(ProgressEvent::StageFinished {
+                stage: stage.name.clone(),
+                duration: started.elapsed(),
+                outcome: output.outcome.clone(),
+            });
+
+            let stage_report = StageReport {
+                name: stage.name.clone(),
+                kind: stage.stage,
+                variant: stage.variant(),
+                output,
+            };
+
+            if let Err(reason) = success {
+                tracing::error!("stage `{}` failed: {}", stage.name, reason);
+                report.outputs.push(stage_report);
+                report.failed = Some(stage.name.clone());
+                state.save(sandbox).await?;
+                break;
+            }
+
+            if let (Some(artifacts), Some(key)) = (&self.artifacts, key) {
+                if !hits.contains(stage.name.as_str()) {
+                    let rustc = rustc_version(&stage_report.output.stdout);
+                    if let Err(err) = artifacts.store(key, sandbox, rustc).await {
+                        tracing::warn!("failed to cache artifacts of `{}`: {:#}", stage.name, err);
+                    }
+                }
+            }
+
+            let resources = &stage_report.output.resources;
+            tracing::info!(
+                wall_time = ?resources.wall_time,
+                cpu_time = ?resources.cpu_time,
+                peak_memory = %ByteSize(resources.peak_memory),
+                "stage `{}` succeeded",
+                stage.name
+            );
+
+            state.completed.push(stage_report.clone());
+            state.save(sandbox).await?;
+            report.outputs.push(stage_report);
+        }
+
+        Ok(report)
+    }
+}
+
+impl Pipeline {
+    /// Computes the artifact keys of every stage that caches its artifacts
+    async fn artifact_keys(
+        &self,
+        sandbox: &Sandbox,
+        profile: &Profile,
+        docker: &Docker,
+        samples: &Samples,
+    ) -> anyhow::Result<HashMap<&str, ArtifactKey>> {
+        let mut keys = HashMap::new();
+        if self.artifacts.is_none() {
+            return Ok(keys);
+        }
+
+        for stage in &self.stages {
+            let image = &profile.stages.get(stage.stage).image;
+            if let Some(key) = ArtifactKey::new(docker, sandbox, stage, image, samples).await? {
+                keys.insert(stage.name.as_str(), key);
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// Returns the stages that must run given the builds that are cached.
+    ///
+    /// A stage can be skipped when every stage that depends on it restores
+    /// its artifacts from the cache or is of an unselected variant,
+    /// benchmarks of selected variants are never skipped.
+    fn needed_stages(&self, hits: &HashSet<&str>, options: &BenchOptions) -> HashSet<&str> {
+        let mut needed = HashSet::new();
+        for stage in self.stages.iter().rev() {
+            if !options.runs_variant(stage.variant()) {
+                continue;
+            }
+
+            let mut dependents = self
+                .stages
+                .iter()
+                .filter(|other| other.depends_on.contains(&stage.name))
+                .peekable();
+
+            let is_sink = dependents.peek().is_none();
+            if is_sink
+                || !stage.stage.is_build_input()
+                || dependents.any(|other| {
+                    needed.contains(other.name.as_str()) && !hits.contains(other.name.as_str())
+                })
+            {
+                needed.insert(stage.name.as_str());
+            }
+        }
+
+        needed
+    }
+}
+
+/// Runs a single pipeline stage on the sandbox
+async fn run_stage(
+    stage: &PipelineStage,
+    sandbox: &Sandbox,
+    profile: &Profile,
+    docker: &Docker,
+    options: &BenchOptions,
+) -> anyhow::Result<ContainerOutput> {
+    let samples = &options.samples;
+    match stage.stage {
+        Stage::Clone => sandbox.clone(profile, docker).await,
+        Stage::Fetch => sandbox.fetch(profile, docker).await,
+        Stage::Build => sandbox.build(profile, docker).await,
+        Stage::BenchE2e => {
+            sandbox
+                .bench_e2e(profile, docker, samples, &options.settings, stage.pgo)
+                .await
+        }
+        Stage::BenchWalltime => {
+            sandbox
+                .bench_walltime(profile, docker, samples, &options.settings, stage.pgo)
+                .await
+        }
+        Stage::PgoBuildProfile => sandbox.pgo_build_profile(profile, docker).await,
+        Stage::PgoProfile => sandbox.pgo_profile(profile, docker, samples).await,
+        Stage::PgoBuild => sandbox.pgo_build(profile, docker).await,
+    }
+}
+
+/// Whether a stage compiles the binary into the target directory
+fn builds_binary(kind: Stage) -> bool {
+    matches!(
+        kind,
+        Stage::Build | Stage::PgoBuildProfile | Stage::PgoBuild
+    )
+}
+
+/// Whether a stage runs the binary of the target directory on the samples,
+/// which also makes it depend on the samples and settings
+fn runs_binary(kind: Stage) -> bool {
+    matches!(
+        kind,
+        Stage::BenchE2e | Stage::BenchWalltime | Stage::PgoProfile
+    )
+}
+
+/// Checks the output of a stage against its success criteria
+async fn check_success(
+    stage: &PipelineStage,
+    sandbox: &Sandbox,
+    output: &ContainerOutput,
+) -> Result<(), String> {
+    match &output.outcome {
+        StageOutcome::Success | StageOutcome::ExitCode(_) => {
+            if !stage.success.exit_codes.contains(&output.exitcode) {
+                return Err(format!("unexpected exit code {}", output.exitcode));
+            }
+        }
+        outcome => return Err(outcome.to_string()),
+    }
+
+    for file in &stage.success.outputs {
+        let path = sandbox.parent.join(file);
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Err(format!("missing output `{}`", file.display()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the stages of a cycle among the stages that could not be ordered,
+/// starting and ending with the same stage. Every such stage still waits for
+/// a dependency that could not be ordered either, so following those
+/// dependencies must eventually revisit a stage.
+fn find_cycle<'a>(stages: &'a [PipelineStage], remaining: &HashMap<&str, usize>) -> Vec<&'a str> {
+    let waiting = |name: &str| {
+        stages
+            .iter()
+            .find(|stage| stage.name == name)
+            .and_then(|stage| {
+                stage
+                    .depends_on
+                    .iter()
+                    .find(|dependency| remaining.contains_key(dependency.as_str()))
+            })
+            .map(String::as_str)
+    };
+
+    let Some(start) = stages
+        .iter()
+        .find(|stage| remaining.contains_key(stage.name.as_str()))
+    else {
+        return Vec::new();
+    };
+
+    let mut path = vec![start.name.as_str()];
+    while let Some(next) = waiting(path[path.len() - 1]) {
+        if let Some(position) = path.iter().position(|name| *name == next) {
+            let mut cycle = path.split_off(position);
+            cycle.push(next);
+            return cycle;
+        }
+
+        path.push(next);
+    }
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::SuccessCriteria;
+
+    fn stage(name: &str, kind: Stage, depends_on: &[&str]) -> PipelineStage {
+        PipelineStage {
+            name: name.to_owned(),
+            stage: kind,
+            depends_on: depends_on.iter().map(|name| name.to_string()).collect(),
+            pgo: false,
+            cache: None,
+            success: SuccessCriteria::default(),
+        }
+    }
+
+    fn names(pipeline: &Pipeline) -> Vec<&str> {
+        pipeline
+            .stages()
+            .iter()
+            .map(|stage| stage.name.as_str())
+            .collect()
+    }
+
+    /// The pipeline of the default profile, where the plain build overwrites
+    /// the PGO binary after it was benchmarked
+    fn pgo_pipeline() -> Pipeline {
+        Pipeline::new(&[
+            stage("clone", Stage::Clone, &[]),
+            stage("fetch", Stage::Fetch, &["clone"]),
+            stage("pgo_build_profile", Stage::PgoBuildProfile, &["fetch"]),
+            stage("pgo_profile", Stage::PgoProfile, &["pgo_build_profile"]),
+            stage("pgo_build", Stage::PgoBuild, &["pgo_profile"]),
+            stage("bench_e2e_pgo", Stage::BenchE2e, &["pgo_build"]),
+            stage("build", Stage::Build, &["fetch", "bench_e2e_pgo"]),
+            stage("bench_e2e", Stage::BenchE2e, &["build"]),
+        ])
+        .unwrap()
+    }
+
+    /// Returns a state in which every stage of a pipeline completed
+    fn completed(pipeline: &Pipeline) -> PipelineState {
+        let output = ContainerOutput::infra_error(&anyhow::anyhow!("not run"));
+        PipelineState {
+            repository: "https://example.com/typst.git".to_owned(),
+            commit: "0123456789".to_owned(),
+            options: String::new(),
+            completed: pipeline
+                .stages()
+                .iter()
+                .map(|stage| StageReport {
+                    name: stage.name.clone(),
+                    kind: stage.stage,
+                    variant: stage.variant(),
+                    output: output.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn completed_names(state: &PipelineState) -> Vec<&str> {
+        state
+            .completed
+            .iter()
+            .map(|stage| stage.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn new_orders_stages_after_their_dependencies() {
+        let pipeline = Pipeline::new(&[
+            stage("bench", Stage::BenchE2e, &["build"]),
+            stage("build", Stage::Build, &["fetch"]),
+            stage("walltime", Stage::BenchWalltime, &["build"]),
+            stage("clone", Stage::Clone, &[]),
+            stage("fetch", Stage::Fetch, &["clone"]),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            names(&pipeline),
+            ["clone", "fetch", "build", "bench", "walltime"]
+        );
+    }
+
+    #[test]
+    fn new_rejects_unknown_dependencies() {
+        let err = Pipeline::new(&[
+            stage("clone", Stage::Clone, &[]),
+            stage("build", Stage::Build, &["clone", "fetch"]),
+        ])
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "pipeline stage `build` depends on unknown stage `fetch`"
+        );
+    }
+
+    #[test]
+    fn new_rejects_duplicate_dependencies() {
+        let err = Pipeline::new(&[
+            stage("clone", Stage::Clone, &[]),
+            stage("fetch", Stage::Fetch, &["clone", "clone"]),
+        ])
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "pipeline stage `fetch` depends on `clone` more than once"
+        );
+    }
+
+    #[test]
+    fn new_reports_only_the_stages_of_a_cycle() {
+        // `bench` waits on the cycle without being part of it.
+        let err = Pipeline::new(&[
+            stage("bench", Stage::BenchE2e, &["fetch"]),
+            stage("clone", Stage::Clone, &[]),
+            stage("fetch", Stage::Fetch, &["clone", "build"]),
+            stage("pgo", Stage::PgoBuild, &["fetch"]),
+            stage("build", Stage::Build, &["pgo"]),
+        ])
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "pipeline contains a cycle: fetch -> build -> pgo -> fetch"
+        );
+    }
+
+    #[test]
+    fn invalidate_from_rebuilds_the_binary_of_a_benchmark() {
+        let pipeline = pgo_pipeline();
+        let mut state = completed(&pipeline);
+        pipeline
+            .invalidate_from(&mut state, "bench_e2e_pgo")
+            .unwrap();
+
+        // The plain build left its binary behind, so the PGO build must run
+        // again before its benchmark.
+        assert_eq!(
+            completed_names(&state),
+            ["clone", "fetch", "pgo_build_profile", "pgo_profile"]
+        );
+
+        let mut state = completed(&pipeline);
+        pipeline.invalidate_from(&mut state, "bench_e2e").unwrap();
+        assert_eq!(
+            completed_names(&state),
+            [
+                "clone",
+                "fetch",
+                "pgo_build_profile",
+                "pgo_profile",
+                "pgo_build",
+                "bench_e2e_pgo"
+            ]
+        );
+    }
+
+    #[test]
+    fn invalidate_from_keeps_stages_before_a_build() {
+        let pipeline = pgo_pipeline();
+        let mut state = completed(&pipeline);
+        pipeline.invalidate_from(&mut state, "build").unwrap();
+
+        assert_eq!(
+            completed_names(&state),
+            [
+                "clone",
+                "fetch",
+                "pgo_build_profile",
+                "pgo_profile",
+                "pgo_build",
+                "bench_e2e_pgo"
+            ]
+        );
+
+        assert!(pipeline.invalidate_from(&mut state, "deploy").is_err());
+    }
+}
@@ -1,9 +1,14 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Component, Path, PathBuf},
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use bytesize::ByteSize;
 use duration_string::DurationString;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use typster_proto::{changepoint::ChangePointSettings, BenchQuery, BuildVariant, Stage};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Profile {
@@ -16,11 +21,35 @@ pub struct Profile {
     /// The stages to use for the sandbox
     pub stages: Stages,
 
+    /// The pipeline of stages to execute, in any order
+    pub pipeline: Vec<PipelineStage>,
+
     /// The sample configuration
     pub samples: Samples,
 
     /// The directory to clone the repository into
     pub workdir: PathBuf,
+
+    /// The caches shared between sandboxes, disabled if not set
+    #[serde(default)]
+    pub cache: Option<CacheSettings>,
+
+    /// The CPU cores available to the stages, no cores are pinned if not set
+    #[serde(default)]
+    pub cores: Option<CoreSettings>,
+
+    /// The history of branches, regressions are not tracked if not set
+    #[serde(default)]
+    pub history: Option<HistorySettings>,
+
+    /// The database every run is recorded in, runs are not recorded if not
+    /// set
+    #[serde(default)]
+    pub database: Option<DatabaseSettings>,
+
+    /// The SHA-256 hash of the profile file
+    #[serde(skip)]
+    pub hash: String,
 }
 
 impl Profile {
@@ -30,7 +59,21 @@ impl Profile {
             .await
             .context("failed to read profile file")?;
 
-        Ok(toml::de::from_str(&file).context("failed to parse profile file")?)
+        let mut profile: Self =
+            toml::de::from_str(&file).context("failed to parse profile file")?;
+        profile.hash = format!("{:x}", Sha256::digest(file.as_bytes()));
+
+        Ok(profile)
+    }
+
+    /// Returns the cache settings if sandboxes share a cargo registry
+    pub fn shared_registry(&self) -> Option<&CacheSettings> {
+        self.cache.as_ref().filter(|cache| cache.shared_registry)
+    }
+
+    /// Returns the cache settings if repositories are cloned from mirrors
+    pub fn git_mirrors(&self) -> Option<&CacheSettings> {
+        self.cache.as_ref().filter(|cache| cache.git_mirrors)
     }
 }
 
@@ -44,6 +87,10 @@ pub struct Samples {
 
     /// The training files for PGO, relative to the root directory
     pub training: Vec<PathBuf>,
+
+    /// Named groups of sample files that queries can select
+    #[serde(default)]
+    pub tags: BTreeMap<String, Vec<PathBuf>>,
 }
 
 impl Samples {
@@ -67,6 +114,42 @@ impl Samples {
             .reduce(|a, b| format!("{},{}", a, b))
     }
 
+    /// Returns the samples restricted to the given files and the files of
+    /// the given tags, or all samples if neither is given
+    pub fn select(&self, files: &[PathBuf], tags: &[String]) -> anyhow::Result<Self> {
+        if files.is_empty() && tags.is_empty() {
+            return Ok(self.clone());
+        }
+
+        let mut selected = Vec::new();
+        for tag in tags {
+            let tagged = self
+                .tags
+                .get(tag)
+                .with_context(|| format!("unknown sample tag `{}`", tag))?;
+            selected.extend(tagged.iter().cloned());
+        }
+
+        for file in files {
+            if !file
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                bail!("sample `{}` is not within the samples", file.display());
+            }
+
+            selected.push(file.clone());
+        }
+
+        let mut seen = HashSet::new();
+        selected.retain(|file| seen.insert(file.clone()));
+
+        Ok(Self {
+            files: selected,
+            ..self.clone()
+        })
+    }
+
     pub fn to_results_file(&self, results: impl AsRef<Path>) -> Vec<PathBuf> {
         self.files
             .iter()
@@ -82,18 +165,263 @@ pub struct Profiles {
     pub main: ProfileSettings,
 
     pub other: ProfileSettings,
+
+    /// Additional settings, selected by their name
+    #[serde(flatten)]
+    pub custom: BTreeMap<String, ProfileSettings>,
+}
+
+impl Profiles {
+    /// Returns the settings with a name
+    pub fn get(&self, name: &str) -> anyhow::Result<&ProfileSettings> {
+        match name {
+            "main" => Ok(&self.main),
+            "other" => Ok(&self.other),
+            _ => self
+                .custom
+                .get(name)
+                .with_context(|| format!("unknown benchmark profile `{}`", name)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Stages {
-    pub clone: Stage,
-    pub fetch: Stage,
-    pub build: Stage,
-    pub bench_e2e: Stage,
-    pub bench_walltime: Stage,
-    pub pgo_build: Stage,
-    pub pgo_build_profile: Stage,
-    pub pgo_profile: Stage,
+    pub clone: StageSettings,
+    pub fetch: StageSettings,
+    pub build: StageSettings,
+    pub bench_e2e: StageSettings,
+    pub bench_walltime: StageSettings,
+    pub pgo_build: StageSettings,
+    pub pgo_build_profile: StageSettings,
+    pub pgo_profile: StageSettings,
+}
+
+impl Stages {
+    /// Returns the stage configuration for a kind of stage
+    pub fn get(&self, kind: Stage) -> &StageSettings {
+        match kind {
+            Stage::Clone => &self.clone,
+            Stage::Fetch => &self.fetch,
+            Stage::Build => &self.build,
+            Stage::BenchE2e => &self.bench_e2e,
+            Stage::BenchWalltime => &self.bench_walltime,
+            Stage::PgoBuild => &self.pgo_build,
+            Stage::PgoBuildProfile => &self.pgo_build_profile,
+            Stage::PgoProfile => &self.pgo_profile,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineStage {
+    /// The unique name of the stage in the pipeline
+    pub name: String,
+
+    /// The kind of stage to run
+    pub stage: Stage,
+
+    /// The names of the stages that must succeed before this one runs
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+
+    /// Whether to benchmark the PGO binary, only used by benchmarking stages
+    #[serde(default)]
+    pub pgo: bool,
+
+    /// The build variant under which the artifacts of the stage are cached,
+    /// only used by building stages
+    #[serde(default)]
+    pub cache: Option<String>,
+
+    /// The conditions under which the stage is considered successful
+    #[serde(default)]
+    pub success: SuccessCriteria,
+}
+
+impl PipelineStage {
+    /// Returns the build variant the stage builds or benchmarks, if it is
+    /// specific to one
+    pub fn variant(&self) -> Option<BuildVariant> {
+        match self.stage {
+            Stage::Clone | Stage::Fetch => None,
+            Stage::Build => Some(BuildVariant::Plain),
+            Stage::PgoBuild | Stage::PgoBuildProfile | Stage::PgoProfile => Some(BuildVariant::Pgo),
+            Stage::BenchE2e | Stage::BenchWalltime if self.pgo => Some(BuildVariant::Pgo),
+            Stage::BenchE2e | Stage::BenchWalltime => Some(BuildVariant::Plain),
+        }
+    }
+}
+
+/// What a pipeline execution benchmarks, from the profile with the
+/// overrides of the command line or of a query applied.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// The samples to benchmark
+    pub samples: Samples,
+
+    /// The benchmark settings
+    pub settings: ProfileSettings,
+
+    /// The build variants to run, every variant if empty
+    pub variants: Vec<BuildVariant>,
+}
+
+impl BenchOptions {
+    /// Applies the options of a query
+    pub fn for_query(&self, profile: &Profile, query: &BenchQuery) -> anyhow::Result<Self> {
+        let files = query.samples.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        Ok(Self {
+            samples: self.samples.select(&files, &query.tags)?,
+            settings: match &query.profile {
+                Some(name) => profile.profiles.get(name)?.clone(),
+                None => self.settings.clone(),
+            },
+            variants: if query.variants.is_empty() {
+                self.variants.clone()
+            } else {
+                query.variants.clone()
+            },
+        })
+    }
+
+    /// Returns a hash of the samples and settings the stages run with
+    pub fn hash(&self) -> String {
+        let options = serde_json::json!([
+            self.samples.root,
+            self.samples.files,
+            self.samples.training,
+            self.settings,
+        ]);
+
+        format!("{:x}", Sha256::digest(options.to_string().as_bytes()))
+    }
+
+    /// Whether stages of a build variant run
+    pub fn runs_variant(&self, variant: Option<BuildVariant>) -> bool {
+        match variant {
+            Some(variant) => self.variants.is_empty() || self.variants.contains(&variant),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SuccessCriteria {
+    /// The exit codes that are considered successful
+    #[serde(default = "default_exit_codes")]
+    pub exit_codes: Vec<i64>,
+
+    /// The files, relative to the sandbox directory, that must exist
+    /// after the stage has run
+    #[serde(default)]
+    pub outputs: Vec<PathBuf>,
+}
+
+impl Default for SuccessCriteria {
+    fn default() -> Self {
+        Self {
+            exit_codes: default_exit_codes(),
+            outputs: Vec::new(),
+        }
+    }
+}
+
+fn default_exit_codes() -> Vec<i64> {
+    vec![0]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheSettings {
+    /// The directory in which the caches are stored
+    pub root: PathBuf,
+
+    /// The cache of built binaries
+    pub artifacts: ArtifactSettings,
+
+    /// Whether sandboxes share a single cargo registry instead of each
+    /// fetching every crate into their own
+    #[serde(default)]
+    pub shared_registry: bool,
+
+    /// Whether repositories are cloned from local bare mirrors that are
+    /// updated incrementally
+    #[serde(default)]
+    pub git_mirrors: bool,
+}
+
+impl CacheSettings {
+    /// Returns the lock file guarding writes to the shared cargo registry
+    pub fn registry_lock(&self) -> PathBuf {
+        self.root.join("cargo.lock")
+    }
+
+    /// Returns the name of the bare mirror of a repository, within the
+    /// mirrors directory
+    pub fn mirror_name(&self, repository: &str) -> String {
+        let hash = format!("{:x}", Sha256::digest(repository.as_bytes()));
+        format!("{}.git", &hash[..16])
+    }
+
+    /// Returns the directory that holds the bare mirrors
+    pub fn mirrors(&self) -> PathBuf {
+        self.root.join("mirrors")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactSettings {
+    /// The maximum total size of the cached artifacts
+    pub max_size: ByteSize,
+
+    /// The maximum number of cached builds
+    pub max_entries: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoreSettings {
+    /// The cores handed out to stages that need exclusive cores, such as
+    /// benchmarks, in the cpuset format used by Docker
+    pub exclusive: String,
+
+    /// The cores shared by every other stage, must not overlap with the
+    /// exclusive cores
+    pub shared: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseSettings {
+    /// The SQLite database file, created if it does not exist
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistorySettings {
+    /// The directory in which the history of every branch is stored
+    pub root: PathBuf,
+
+    /// The relative slowdown below which a change point is not reported,
+    /// such as `0.01` for 1%
+    #[serde(default = "default_min_change")]
+    pub min_change: f64,
+
+    /// The number of runs by which a change point may drift as commits are
+    /// added around it and still count as the one that was reported
+    #[serde(default = "default_alert_window")]
+    pub alert_window: usize,
+
+    /// How change points are detected
+    #[serde(default)]
+    pub detection: ChangePointSettings,
+}
+
+fn default_min_change() -> f64 {
+    0.01
+}
+
+fn default_alert_window() -> usize {
+    5
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -106,7 +434,7 @@ pub struct ProfileSettings {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Stage {
+pub struct StageSettings {
     pub image: String,
     pub soft_timeout: DurationString,
     pub hard_timeout: DurationString,
@@ -114,5 +442,9 @@ pub struct Stage {
     pub swap_limit: ByteSize,
     pub cpu_limit: f64,
     pub networking: bool,
-    pub cpu_cores: Option<String>,
+
+    /// The number of cores the stage needs for itself, taken from the
+    /// exclusive pool
+    #[serde(default)]
+    pub exclusive_cores: Option<usize>,
 }
@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use typster_proto::{BenchProgress, ProgressEvent};
+
+/// The prefix of the lines benchmark containers print after every sample,
+/// followed by `<done>/<total> <sample>`
+pub const PROGRESS_MARKER: &str = "typster-progress ";
+
+/// Reports the progress of the pipeline of a sandbox to whoever listens.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    id: String,
+    tx: UnboundedSender<BenchProgress>,
+}
+
+impl Progress {
+    /// Creates a reporter for a sandbox and the receiving end of its events,
+    /// which is closed once every reporter is dropped
+    pub fn channel(id: impl Into<String>) -> (Self, UnboundedReceiver<BenchProgress>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self { id: id.into(), tx }, rx)
+    }
+
+    /// Reports an event, ignoring it if nobody listens anymore
+    pub fn send(&self, event: ProgressEvent) {
+        self.tx
+            .send(BenchProgress {
+                id: self.id.clone(),
+                event,
+            })
+            .ok();
+    }
+
+    /// Reports a progress line printed by a benchmark container, ignoring
+    /// lines that are not progress markers
+    pub fn sample_line(&self, line: &str, elapsed: Duration) {
+        let Some((done, total, sample)) = parse_marker(line) else {
+            return;
+        };
+
+        // Assume the remaining samples take as long as the finished ones.
+        let eta = (done > 0).then(|| elapsed.mul_f64(f64::from(total - done) / f64::from(done)));
+
+        self.send(ProgressEvent::SampleDone {
+            sample: sample.to_owned(),
+            done,
+            total,
+            eta,
+        });
+    }
+}
+
+fn parse_marker(line: &str) -> Option<(u32, u32, &str)> {
+    let rest = line.strip_prefix(PROGRESS_MARKER)?;
+    let (counts, sample) = rest.split_once(' ').unwrap_or((rest, ""));
+    let (done, total) = counts.split_once('/')?;
+    let (done, total) = (done.parse().ok()?, total.parse().ok()?);
+
+    (done <= total).then_some((done, total, sample.trim()))
+}
@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+
+use bollard::{models::SystemInfo, Docker};
+use sha2::{Digest, Sha256};
+use typster_proto::Provenance;
+
+use crate::{pipeline::PipelineReport, profile::Profile};
+
+/// The prefix of the line build containers print with the version of rustc
+pub const RUSTC_MARKER: &str = "typster-rustc ";
+
+/// Records the host, the Docker engine, the images and the toolchain that
+/// produced the outputs of a pipeline
+///
+/// This is best-effort, such that results are not lost to a flaky engine:
+/// whatever cannot be queried is logged and left out.
+pub async fn collect(docker: &Docker, profile: &Profile, report: &PipelineReport) -> Provenance {
+    let info = match docker.info().await {
+        Ok(info) => info,
+        Err(err) => {
+            tracing::warn!("failed to query the Docker engine for provenance: {}", err);
+            SystemInfo::default()
+        }
+    };
+
+    let mut images = BTreeMap::new();
+    let mut cpusets = BTreeMap::new();
+    for stage in &report.outputs {
+        if let Some(cpuset) = &stage.output.cpuset {
+            cpusets.insert(stage.name.clone(), cpuset.clone());
+        }
+
+        let image = &profile.stages.get(stage.kind).image;
+        if images.contains_key(image) {
+            continue;
+        }
+
+        match docker.inspect_image(image).await.map(|inspect| inspect.id) {
+            Ok(Some(digest)) => {
+                images.insert(image.clone(), digest);
+            }
+            Ok(None) => tracing::warn!("image `{}` has no digest", image),
+            Err(err) => tracing::warn!("failed to inspect image `{}`: {}", image, err),
+        }
+    }
+
+    let cgroup = match (info.cgroup_version, info.cgroup_driver) {
+        (Some(version), Some(driver)) => format!("{} ({})", version, driver),
+        (Some(version), None) => version.to_string(),
+        (None, _) => String::new(),
+    };
+
+    Provenance {
+        cpu_model: cpu_model().await.unwrap_or_default(),
+        cores: info.ncpu.and_then(|n| n.try_into().ok()).unwrap_or(0),
+        kernel: info.kernel_version.unwrap_or_default(),
+        docker: info.server_version.unwrap_or_default(),
+        cgroup,
+        cpusets,
+        images,
+        rustc: report
+            .outputs
+            .iter()
+            .filter(|stage| stage.kind.is_build())
+            .find_map(|stage| rustc_version(&stage.output.stdout)),
+        runner: env!("CARGO_PKG_VERSION").to_owned(),
+        profile_hash: profile.hash.clone(),
+    }
+}
+
+/// Returns the environment of a result, as a hash of the host and the
+/// profile that produced it, which results must share to be compared
+pub fn environment(provenance: &Provenance) -> String {
+    let environment = format!(
+        "{}\n{}\n{}",
+        provenance.cpu_model, provenance.cores, provenance.profile_hash
+    );
+    format!("{:x}", Sha256::digest(environment.as_bytes()))[..16].to_owned()
+}
+
+/// Returns the version of rustc a build printed, if any
+pub fn rustc_version(stdout: &[String]) -> Option<String> {
+    stdout
+        .iter()
+        .find_map(|line| line.strip_prefix(RUSTC_MARKER))
+        .map(|version| version.trim().to_owned())
+}
+
+/// Returns the model name of the CPU of the host the runner runs on
+async fn cpu_model() -> Option<String> {
+    let cpuinfo = tokio::fs::read_to_string("/proc/cpuinfo").await.ok()?;
+    cpuinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "model name")
+        .map(|(_, model)| model.trim().to_owned())
+}
@@ -0,0 +1,61 @@
+// The queues are only driven from the worker's own task, so their futures
+// need not be `Send`.
+#![allow(async_fn_in_trait)]
+
+use typster_proto::{BenchProgress, BenchResult, Encoding, RegressionAlert};
+
+pub mod amqp;
+pub mod spool;
+
+/// A benchmark query taken from a [`JobQueue`].
+pub trait Job {
+    /// The serialized query
+    fn data(&self) -> &[u8];
+
+    /// The content type naming the encoding of the query, if any
+    fn content_type(&self) -> Option<&str>;
+
+    /// How many attempts at running the query already failed
+    fn attempts(&self) -> u32;
+}
+
+/// A durable source of benchmark queries.
+///
+/// A job stays in the queue until it is acknowledged, retried or
+/// dead-lettered, such that a worker dying while running it never loses it.
+pub trait JobQueue {
+    type Job: Job;
+
+    /// Waits for the next job, returning `None` once the queue is closed.
+    ///
+    /// This is not cancel-safe: a job may already be taken from the queue
+    /// when the future is dropped, so it must be polled to completion.
+    async fn next(&self) -> anyhow::Result<Option<Self::Job>>;
+
+    /// Removes a job that has been handled from the queue
+    async fn ack(&self, job: &Self::Job) -> anyhow::Result<()>;
+
+    /// Puts a job that failed back into the queue, counting the attempt
+    async fn retry(&self, job: &Self::Job, reason: &str) -> anyhow::Result<()>;
+
+    /// Moves a job that cannot succeed out of the queue
+    async fn dead_letter(&self, job: &Self::Job, reason: &str) -> anyhow::Result<()>;
+
+    /// Gives up on a job that could not be handled, making it available
+    /// again without acknowledging it
+    async fn release(&self, job: &Self::Job) -> anyhow::Result<()>;
+}
+
+/// A destination for benchmark results.
+pub trait ResultSink {
+    /// Durably publishes a result, in the encoding of the query it answers
+    /// where the sink supports it
+    async fn publish(&self, result: &BenchResult, encoding: Encoding) -> anyhow::Result<()>;
+
+    /// Publishes a progress event of a running query, on a best effort
+    /// basis as events are not worth retrying
+    async fn progress(&self, progress: &BenchProgress, encoding: Encoding) -> anyhow::Result<()>;
+
+    /// Durably publishes a regression found in the history of a branch
+    async fn alert(&self, alert: &RegressionAlert, encoding: Encoding) -> anyhow::Result<()>;
+}
@@ -0,0 +1,364 @@
+use anyhow::Context;
+use futures_util::StreamExt;
+use lapin::{
+    message::Delivery,
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
+        BasicQosOptions, ConfirmSelectOptions, QueueDeclareOptions,
+    },
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, Connection, ConnectionProperties, Consumer,
+};
+use tokio::sync::Mutex;
+use typster_proto::{BenchProgress, BenchQuery, BenchResult, Encoding, RegressionAlert};
+
+use super::{Job, JobQueue, ResultSink};
+use crate::config::WorkerArgs;
+
+/// The header counting how many times a query has been attempted
+const ATTEMPTS_HEADER: &str = "x-typster-attempts";
+
+/// The header holding why a query was dead-lettered
+const DEATH_REASON_HEADER: &str = "x-typster-reason";
+
+/// Consumes benchmark queries from a queue of an AMQP broker.
+///
+/// Retried queries are published again with an incremented attempt counter
+/// before the original is acknowledged, since the broker itself does not
+/// count deliveries.
+pub struct AmqpQueue {
+    _conn: Connection,
+    channel: Channel,
+    consumer: Mutex<Consumer>,
+    queue: String,
+    dead_letter_queue: String,
+}
+
+/// A query delivered by the broker.
+pub struct AmqpJob {
+    delivery: Delivery,
+    attempts: u32,
+}
+
+/// Publishes benchmark results, progress and alerts to queues of an AMQP
+/// broker.
+pub struct AmqpSink {
+    channel: Channel,
+    queue: String,
+    progress_queue: String,
+    alerts_queue: String,
+}
+
+/// Connects to the broker, declares the queues and starts consuming
+pub async fn connect(args: &WorkerArgs) -> anyhow::Result<(AmqpQueue, AmqpSink)> {
+    let (conn, channel) = open(&args.amqp_addr).await?;
+
+    let prefetch = args.bench.jobs.max(1).try_into().unwrap_or(u16::MAX);
+    channel
+        .basic_qos(prefetch, BasicQosOptions::default())
+        .await
+        .context("failed to set prefetch count")?;
+
+    // The broker orders queries by the priority of the AMQP message, which
+    // producers set to the priority of the query.
+    let mut arguments = FieldTable::default();
+    if let Some(priority) = args.max_priority {
+        arguments.insert("x-max-priority".into(), AMQPValue::ShortShortUInt(priority));
+    }
+
+    for (queue, arguments) in [
+        (&args.queue, arguments),
+        (&args.results_queue, FieldTable::default()),
+        (&args.progress_queue, FieldTable::default()),
+        (&args.alerts_queue, FieldTable::default()),
+        (&args.dead_letter_queue, FieldTable::default()),
+    ] {
+        channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                arguments,
+            )
+            .await?;
+    }
+
+    let consumer = channel
+        .basic_consume(
+            &args.queue,
+            "bencher",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    let sink = AmqpSink {
+        channel: channel.clone(),
+        queue: args.results_queue.clone(),
+        progress_queue: args.progress_queue.clone(),
+        alerts_queue: args.alerts_queue.clone(),
+    };
+
+    let queue = AmqpQueue {
+        _conn: conn,
+        channel,
+        consumer: Mutex::new(consumer),
+        queue: args.queue.clone(),
+        dead_letter_queue: args.dead_letter_queue.clone(),
+    };
+
+    Ok((queue, sink))
+}
+
+impl Job for AmqpJob {
+    fn data(&self) -> &[u8] {
+        &self.delivery.data
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        self.delivery
+            .properties
+            .content_type()
+            .as_ref()
+            .map(|content_type| content_type.as_str())
+    }
+
+    fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+impl JobQueue for AmqpQueue {
+    type Job = AmqpJob;
+
+    async fn next(&self) -> anyhow::Result<Option<AmqpJob>> {
+        let mut consumer = self.consumer.lock().await;
+        loop {
+            let Some(delivery) = consumer.next().await else {
+                return Ok(None);
+            };
+
+            let delivery = delivery.context("failed to receive query")?;
+            let attempts = attempts(&delivery);
+
+            // The broker only redelivers a query when the worker running it
+            // died or released it, which counts as a failed attempt. The
+            // count is persisted first such that a query crashing every
+            // worker is eventually dead-lettered.
+            if delivery.redelivered {
+                self.requeue(&delivery, attempts + 1).await?;
+                continue;
+            }
+
+            return Ok(Some(AmqpJob { delivery, attempts }));
+        }
+    }
+
+    async fn ack(&self, job: &AmqpJob) -> anyhow::Result<()> {
+        ack(&job.delivery).await
+    }
+
+    async fn retry(&self, job: &AmqpJob, _: &str) -> anyhow::Result<()> {
+        self.requeue(&job.delivery, job.attempts + 1).await
+    }
+
+    async fn dead_letter(&self, job: &AmqpJob, reason: &str) -> anyhow::Result<()> {
+        let properties = with_header(
+            &job.delivery,
+            DEATH_REASON_HEADER,
+            AMQPValue::LongString(reason.into()),
+        );
+
+        publish(
+            &self.channel,
+            &self.dead_letter_queue,
+            &job.delivery.data,
+            properties,
+        )
+        .await?;
+        ack(&job.delivery).await
+    }
+
+    async fn release(&self, job: &AmqpJob) -> anyhow::Result<()> {
+        job.delivery
+            .nack(BasicNackOptions {
+                requeue: true,
+                ..Default::default()
+            })
+            .await
+            .context("Failed to nack message")
+    }
+}
+
+impl AmqpQueue {
+    /// Publishes a copy of a query with a new attempt count, then
+    /// acknowledges the original such that the query survives a crash in
+    /// between
+    async fn requeue(&self, delivery: &Delivery, attempts: u32) -> anyhow::Result<()> {
+        let properties = with_header(
+            delivery,
+            ATTEMPTS_HEADER,
+            AMQPValue::LongLongInt(attempts.into()),
+        );
+
+        publish(&self.channel, &self.queue, &delivery.data, properties).await?;
+        ack(delivery).await
+    }
+}
+
+impl ResultSink for AmqpSink {
+    async fn publish(&self, result: &BenchResult, encoding: Encoding) -> anyhow::Result<()> {
+        let data = encoding.encode(result)?;
+        let properties =
+            BasicProperties::default().with_content_type(encoding.content_type().into());
+        publish(&self.channel, &self.queue, &data, properties).await
+    }
+
+    async fn progress(&self, progress: &BenchProgress, encoding: Encoding) -> anyhow::Result<()> {
+        let data = encoding.encode(progress)?;
+        let properties =
+            BasicProperties::default().with_content_type(encoding.content_type().into());
+
+        // Progress is transient, so it is neither persisted nor confirmed.
+        self.channel
+            .basic_publish(
+                "",
+                &self.progress_queue,
+                BasicPublishOptions::default(),
+                &data,
+                properties,
+            )
+            .await
+            .context("Failed to publish progress")?;
+
+        Ok(())
+    }
+
+    async fn alert(&self, alert: &RegressionAlert, encoding: Encoding) -> anyhow::Result<()> {
+        let data = encoding.encode(alert)?;
+        let properties =
+            BasicProperties::default().with_content_type(encoding.content_type().into());
+        publish(&self.channel, &self.alerts_queue, &data, properties).await
+    }
+}
+
+/// Submits benchmark queries to the queue of an AMQP broker.
+pub struct AmqpSubmitter {
+    _conn: Connection,
+    channel: Channel,
+    queue: String,
+}
+
+impl AmqpSubmitter {
+    /// Connects to the broker, which must already have the queue
+    pub async fn connect(addr: &str, queue: &str) -> anyhow::Result<Self> {
+        let (conn, channel) = open(addr).await?;
+
+        // The queue is declared by the workers, whose arguments a
+        // redeclaration would have to match.
+        channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    passive: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .with_context(|| format!("queue `{}` does not exist", queue))?;
+
+        Ok(Self {
+            _conn: conn,
+            channel,
+            queue: queue.to_owned(),
+        })
+    }
+
+    /// Durably publishes a query, with its priority
+    pub async fn submit(&self, query: &BenchQuery) -> anyhow::Result<()> {
+        let encoding = Encoding::Bincode;
+        let data = encoding.encode(query)?;
+        let properties = BasicProperties::default()
+            .with_content_type(encoding.content_type().into())
+            .with_priority(query.priority);
+        publish(&self.channel, &self.queue, &data, properties).await
+    }
+}
+
+/// Connects to the broker and opens a channel with publisher confirms
+async fn open(addr: &str) -> anyhow::Result<(Connection, Channel)> {
+    let conn = Connection::connect(
+        addr,
+        ConnectionProperties::default()
+            .with_executor(tokio_executor_trait::Tokio::current())
+            .with_reactor(tokio_reactor_trait::Tokio),
+    )
+    .await
+    .context("failed to connect to AMQP broker")?;
+    tracing::info!("Connected to AMQP broker");
+
+    let channel = conn.create_channel().await?;
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await
+        .context("failed to enable publisher confirms")?;
+
+    Ok((conn, channel))
+}
+
+/// Returns how many attempts of a query already failed
+fn attempts(delivery: &Delivery) -> u32 {
+    delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(ATTEMPTS_HEADER))
+        .and_then(AMQPValue::as_long_long_int)
+        .and_then(|attempts| attempts.try_into().ok())
+        .unwrap_or(0)
+}
+
+/// Returns the properties of a delivery with a header set
+fn with_header(delivery: &Delivery, name: &str, value: AMQPValue) -> BasicProperties {
+    let mut headers = delivery.properties.headers().clone().unwrap_or_default();
+    headers.insert(name.into(), value);
+
+    delivery.properties.clone().with_headers(headers)
+}
+
+async fn ack(delivery: &Delivery) -> anyhow::Result<()> {
+    delivery
+        .ack(BasicAckOptions::default())
+        .await
+        .context("Failed to ack message")
+}
+
+/// Publishes a message and waits until the broker confirmed it
+async fn publish(
+    channel: &Channel,
+    queue: &str,
+    data: &[u8],
+    properties: BasicProperties,
+) -> anyhow::Result<()> {
+    let confirmation = channel
+        .basic_publish(
+            "",
+            queue,
+            BasicPublishOptions::default(),
+            data,
+            properties.with_delivery_mode(2),
+        )
+        .await
+        .context("Failed to publish")?
+        .await
+        .context("Failed to wait for confirmation")?;
+
+    if confirmation.is_nack() {
+        anyhow::bail!("the broker rejected the message");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,402 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use typster_proto::{
+    deserialize_from, BenchProgress, BenchQuery, BenchResult, Encoding, RegressionAlert,
+};
+
+use super::{Job, JobQueue, ResultSink};
+use crate::{lock::FileLock, runs::random_id};
+
+/// How often an empty spool is checked for new jobs
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A durable job queue in a local directory, for setups without a broker.
+///
+/// Every job is a file that moves from `pending` to `running` when it is
+/// taken, highest priority and oldest first, and is removed or moved to
+/// `dead` once it was handled. Results are written to `results` as JSON,
+/// whatever the encoding of their query, and the progress of every query is
+/// appended to `progress/<id>.jsonl` as JSON lines. Regressions found in the
+/// history of branches are written to `alerts` as JSON. The file of every
+/// job is named in `index/<id>`, such that queries are found without reading
+/// the whole queue. A single worker may use a spool at a time, so jobs left
+/// in `running` when it opens belong to a worker that died.
+pub struct SpoolQueue {
+    root: PathBuf,
+    _lock: FileLock,
+}
+
+/// A job taken from a spool.
+pub struct SpoolJob {
+    path: PathBuf,
+    entry: SpoolEntry,
+}
+
+/// Writes benchmark results, progress and alerts into a spool.
+pub struct SpoolSink {
+    root: PathBuf,
+}
+
+/// Where a query is in a spool.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpoolStatus {
+    pub state: SpoolState,
+
+    /// The number of attempts that failed
+    pub attempts: u32,
+
+    /// Why the last attempt failed, if one did
+    pub reason: Option<String>,
+}
+
+/// The directory of a spool a query is in.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpoolState {
+    Pending,
+    Running,
+    Dead,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SpoolEntry {
+    priority: u8,
+    attempts: u32,
+    reason: Option<String>,
+    data: Vec<u8>,
+}
+
+const PENDING: &str = "pending";
+const RUNNING: &str = "running";
+const DEAD: &str = "dead";
+const RESULTS: &str = "results";
+const PROGRESS: &str = "progress";
+const ALERTS: &str = "alerts";
+const INDEX: &str = "index";
+
+impl SpoolQueue {
+    /// Opens the spool in a directory, creating it if needed, and requeues
+    /// the jobs of a previous worker that died
+    pub async fn open(root: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let root = root.as_ref().to_owned();
+        create_dirs(&root).await?;
+
+        let Some(lock) = FileLock::try_exclusive(root.join("spool.lock"))? else {
+            bail!("spool {} is used by another worker", root.display());
+        };
+
+        let queue = Self { root, _lock: lock };
+        for path in list(&queue.root.join(RUNNING)).await? {
+            let mut entry = read_entry(&path).await?;
+            entry.attempts += 1;
+            entry.reason = Some("worker died".into());
+
+            tracing::warn!("requeuing interrupted job {}", path.display());
+            queue.move_to(&path, PENDING, &entry).await?;
+        }
+
+        Ok(queue)
+    }
+
+    /// Returns the sink writing results into this spool
+    pub fn results(&self) -> SpoolSink {
+        SpoolSink {
+            root: self.root.clone(),
+        }
+    }
+
+    /// Writes an entry into a directory of the spool under a new name and
+    /// removes the file it came from
+    async fn move_to(&self, from: &Path, dir: &str, entry: &SpoolEntry) -> anyhow::Result<()> {
+        let name = job_name(entry.priority);
+        write_file(
+            &self.root,
+            &self.root.join(dir).join(&name),
+            &encode(entry)?,
+        )
+        .await?;
+        index(&self.root, entry, &name).await?;
+        tokio::fs::remove_file(from)
+            .await
+            .with_context(|| format!("failed to remove {}", from.display()))
+    }
+}
+
+/// Adds a query to the spool in a directory, creating it if needed
+pub async fn submit(root: impl AsRef<Path>, query: &BenchQuery) -> anyhow::Result<()> {
+    let root = root.as_ref();
+    create_dirs(root).await?;
+
+    let entry = SpoolEntry {
+        priority: query.priority,
+        attempts: 0,
+        reason: None,
+        data: typster_proto::encode(query)?,
+    };
+
+    let name = job_name(query.priority);
+    write_file(root, &root.join(PENDING).join(&name), &encode(&entry)?).await?;
+    index(root, &entry, &name).await
+}
+
+/// Finds the query with an ID in the spool in a directory, returning `None`
+/// if it is not in the spool, such as after it was handled
+pub async fn status(root: impl AsRef<Path>, id: &str) -> anyhow::Result<Option<SpoolStatus>> {
+    let root = root.as_ref();
+
+    // A job that is requeued while it is looked up gets a new name, which
+    // the index holds by the second attempt.
+    for _ in 0..2 {
+        let name = match tokio::fs::read_to_string(index_path(root, id)).await {
+            Ok(name) => name,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("failed to read spool index"),
+        };
+
+        // Taken jobs keep their name as they move from pending to running.
+        for (dir, state) in [
+            (PENDING, SpoolState::Pending),
+            (RUNNING, SpoolState::Running),
+            (DEAD, SpoolState::Dead),
+        ] {
+            let Ok(entry) = read_entry(&root.join(dir).join(name.trim())).await else {
+                continue;
+            };
+
+            // IDs that are not safe in file names may share an index file.
+            if query_id(&entry).as_deref() != Some(id) {
+                return Ok(None);
+            }
+
+            return Ok(Some(SpoolStatus {
+                state,
+                attempts: entry.attempts,
+                reason: entry.reason,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+impl Job for SpoolJob {
+    fn data(&self) -> &[u8] {
+        &self.entry.data
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        None
+    }
+
+    fn attempts(&self) -> u32 {
+        self.entry.attempts
+    }
+}
+
+impl JobQueue for SpoolQueue {
+    type Job = SpoolJob;
+
+    async fn next(&self) -> anyhow::Result<Option<SpoolJob>> {
+        loop {
+            for pending in list(&self.root.join(PENDING)).await? {
+                let path = self.root.join(RUNNING).join(pending.file_name().unwrap());
+                match tokio::fs::rename(&pending, &path).await {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err).context("failed to take job"),
+                }
+
+                match read_entry(&path).await {
+                    Ok(entry) => return Ok(Some(SpoolJob { path, entry })),
+                    Err(err) => {
+                        tracing::error!("moving unreadable job to dead letters: {:#}", err);
+                        let dead = self.root.join(DEAD).join(path.file_name().unwrap());
+                        tokio::fs::rename(&path, &dead)
+                            .await
+                            .context("failed to dead-letter job")?;
+                    }
+                }
+            }
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn ack(&self, job: &SpoolJob) -> anyhow::Result<()> {
+        tokio::fs::remove_file(&job.path)
+            .await
+            .context("failed to remove handled job")?;
+
+        let Some(id) = query_id(&job.entry) else {
+            return Ok(());
+        };
+
+        match tokio::fs::remove_file(index_path(&self.root, &id)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).context("failed to remove handled job from the index")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn retry(&self, job: &SpoolJob, reason: &str) -> anyhow::Result<()> {
+        let entry = SpoolEntry {
+            attempts: job.entry.attempts + 1,
+            reason: Some(reason.into()),
+            ..job.entry.clone()
+        };
+
+        self.move_to(&job.path, PENDING, &entry).await
+    }
+
+    async fn dead_letter(&self, job: &SpoolJob, reason: &str) -> anyhow::Result<()> {
+        let entry = SpoolEntry {
+            reason: Some(reason.into()),
+            ..job.entry.clone()
+        };
+
+        self.move_to(&job.path, DEAD, &entry).await
+    }
+
+    async fn release(&self, job: &SpoolJob) -> anyhow::Result<()> {
+        self.retry(job, "released by worker").await
+    }
+}
+
+impl ResultSink for SpoolSink {
+    async fn publish(&self, result: &BenchResult, _: Encoding) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(result).context("failed to serialize result")?;
+        let path = self.root.join(RESULTS).join(file_name("json"));
+        write_file(&self.root, &path, &data).await
+    }
+
+    async fn progress(&self, progress: &BenchProgress, _: Encoding) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(progress).context("failed to serialize progress")?;
+        line.push(b'\n');
+
+        let path = self
+            .root
+            .join(PROGRESS)
+            .join(format!("{}.jsonl", file_stem(&progress.id)));
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        file.write_all(&line)
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    async fn alert(&self, alert: &RegressionAlert, _: Encoding) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(alert).context("failed to serialize alert")?;
+        let path = self.root.join(ALERTS).join(file_name("json"));
+        write_file(&self.root, &path, &data).await
+    }
+}
+
+async fn create_dirs(root: &Path) -> anyhow::Result<()> {
+    for dir in [PENDING, RUNNING, DEAD, RESULTS, PROGRESS, ALERTS, INDEX] {
+        tokio::fs::create_dir_all(root.join(dir))
+            .await
+            .with_context(|| format!("failed to create spool directory {}", dir))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the files of a spool directory in name order
+async fn list(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("failed to read {}", dir.display()))?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            files.push(entry.path());
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Names the file of the job of an entry in the index, if it holds a query
+async fn index(root: &Path, entry: &SpoolEntry, name: &str) -> anyhow::Result<()> {
+    match query_id(entry) {
+        Some(id) => write_file(root, &index_path(root, &id), name.as_bytes()).await,
+        None => Ok(()),
+    }
+}
+
+/// Returns the index file of a query ID
+fn index_path(root: &Path, id: &str) -> PathBuf {
+    root.join(INDEX).join(file_stem(id))
+}
+
+/// Returns the ID of the query of an entry, if it can be decoded
+fn query_id(entry: &SpoolEntry) -> Option<String> {
+    typster_proto::decode::<BenchQuery>(&entry.data)
+        .ok()
+        .map(|query| query.id)
+}
+
+/// Returns a unique job file name that sorts by priority, then by creation
+/// time
+fn job_name(priority: u8) -> String {
+    format!("{:03}-{}", u8::MAX - priority, file_name("job"))
+}
+
+/// Returns a query ID with every character that is not safe in a file name
+/// replaced
+fn file_stem(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Returns a unique file name that sorts by creation time
+fn file_name(extension: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    format!("{:020}-{}.{}", now, random_id(8), extension)
+}
+
+/// Writes a file atomically, through a temporary file in the spool root
+async fn write_file(root: &Path, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let tmp = root.join(format!(".tmp-{}", random_id(8)));
+    tokio::fs::write(&tmp, data)
+        .await
+        .context("failed to write spool file")?;
+
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn encode(entry: &SpoolEntry) -> anyhow::Result<Vec<u8>> {
+    typster_proto::serialize(entry).context("failed to serialize spool entry")
+}
+
+async fn read_entry(path: &Path) -> anyhow::Result<SpoolEntry> {
+    let data = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    deserialize_from(&data[..]).with_context(|| format!("invalid spool entry {}", path.display()))
+}
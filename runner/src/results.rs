@@ -1,6 +1,8 @@
-use std::time::Duration;
+use std::{path::Path, time::Duration};
 
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use typster_proto::{SampleMetric, Summary};
 
 #[derive(Serialize, Deserialize)]
 pub struct BenchmarkResults {
@@ -15,6 +17,13 @@ pub struct BenchmarkResults {
     pub samples: Vec<f64>,
 }
 
+impl BenchmarkResults {
+    /// Returns the statistics of the samples, if there are any
+    pub fn summary(&self) -> Option<Summary> {
+        Summary::of(&self.samples)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SamplingResults {
     /// The configuration used for sampling.
@@ -24,6 +33,69 @@ pub struct SamplingResults {
     pub samples: Vec<BenchmarkResults>,
 }
 
+impl SamplingResults {
+    /// Loads the results of a sample from a JSON file
+    pub async fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("failed to open bench output file {}", path.display()))?;
+
+        serde_json::from_slice(&file)
+            .with_context(|| format!("failed to parse bench output file {}", path.display()))
+    }
+
+    /// Loads the results of every sample in a results directory, or a single
+    /// results file, sorted by sample name.
+    pub async fn load_all(path: impl AsRef<Path>) -> anyhow::Result<Vec<(String, Self)>> {
+        let path = path.as_ref();
+        let mut files = Vec::new();
+        if tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .is_dir()
+        {
+            let mut entries = tokio::fs::read_dir(path)
+                .await
+                .context("failed to read results directory")?;
+
+            while let Some(entry) = entries.next_entry().await? {
+                let file = entry.path();
+                if file.extension().is_some_and(|ext| ext == "json") {
+                    files.push(file);
+                }
+            }
+        } else {
+            files.push(path.to_owned());
+        }
+
+        files.sort();
+
+        let mut results = Vec::with_capacity(files.len());
+        for file in files {
+            results.push((sample_name(&file), Self::load(&file).await?));
+        }
+
+        Ok(results)
+    }
+
+    /// Returns the samples of a metric, if it was measured
+    pub fn metric(&self, metric: Metric) -> Option<&[f64]> {
+        self.samples
+            .iter()
+            .find(|s| s.metric == metric)
+            .map(|s| s.samples.as_slice())
+    }
+}
+
+/// Returns the name of a sample from its results file
+pub fn sample_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into()
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Metric {
     /// The time, in nano-seconds, that the command took to execute.
@@ -45,6 +117,19 @@ pub enum Metric {
     SystemCpuTime,
 }
 
+impl From<Metric> for SampleMetric {
+    fn from(metric: Metric) -> Self {
+        match metric {
+            Metric::Time => Self::Walltime,
+            Metric::VirtualMemory => Self::VirtualMemory,
+            Metric::ResidentMemory => Self::ResidentMemory,
+            Metric::Load => Self::CpuPercent,
+            Metric::UserCpuTime => Self::UserTime,
+            Metric::SystemCpuTime => Self::SystemTime,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct SampleConfig {
     pub n_warmup: usize,
@@ -0,0 +1,126 @@
+use std::{collections::HashMap, path::PathBuf, sync::OnceLock};
+
+use anyhow::Context;
+use bollard::{
+    container::{ListContainersOptions, RemoveContainerOptions},
+    Docker,
+};
+use rand::Rng;
+
+use crate::lock::FileLock;
+
+/// The label holding the ID of the sandbox a container belongs to
+pub const SANDBOX_LABEL: &str = "typster.sandbox";
+
+/// The label holding the name of the stage a container runs
+pub const STAGE_LABEL: &str = "typster.stage";
+
+/// The label holding the ID of the runner process that created a container
+pub const RUN_LABEL: &str = "typster.run";
+
+static RUN_ID: OnceLock<String> = OnceLock::new();
+
+/// Returns the ID of this runner process, used to label its containers
+pub fn run_id() -> &'static str {
+    RUN_ID.get_or_init(|| random_id(10))
+}
+
+/// Returns a random alphanumeric ID
+pub fn random_id(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// The directory holding one lock file per live runner process
+fn runs_dir() -> PathBuf {
+    std::env::temp_dir().join("typster-runs")
+}
+
+/// Marks this runner process as alive for as long as it is held.
+///
+/// Other runner processes use it to tell the containers of live runs apart
+/// from the orphans of runs that crashed.
+pub struct Run {
+    _lock: FileLock,
+}
+
+impl Run {
+    /// Registers this runner process as alive
+    pub async fn start() -> anyhow::Result<Self> {
+        let dir = runs_dir();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .context("failed to create runs directory")?;
+
+        let lock = FileLock::exclusive(dir.join(format!("{}.lock", run_id()))).await?;
+
+        Ok(Self { _lock: lock })
+    }
+}
+
+/// Stops and removes every labelled container left over by runs that are no
+/// longer alive
+pub async fn sweep(docker: &Docker) -> anyhow::Result<()> {
+    let containers = docker
+        .list_containers(Some(ListContainersOptions::<&str> {
+            all: true,
+            filters: HashMap::from([("label", vec![RUN_LABEL])]),
+            ..Default::default()
+        }))
+        .await
+        .context("failed to list containers")?;
+
+    let dir = runs_dir();
+    let mut alive = HashMap::new();
+    for container in containers {
+        let (Some(id), Some(run)) = (
+            container.id,
+            container.labels.and_then(|mut l| l.remove(RUN_LABEL)),
+        ) else {
+            continue;
+        };
+
+        if run == run_id() {
+            continue;
+        }
+
+        // A run is alive as long as its process holds the lock on its file.
+        if !alive.contains_key(&run) {
+            let lock = dir.join(format!("{}.lock", run));
+            let dead = FileLock::try_exclusive(&lock)?;
+            alive.insert(run.clone(), dead.is_none());
+        }
+
+        if alive[&run] {
+            continue;
+        }
+
+        tracing::warn!(container = %id, run = %run, "removing orphaned container");
+        if let Err(err) = docker
+            .remove_container(
+                &id,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+        {
+            tracing::error!("failed to remove orphaned container: {}", err);
+        }
+    }
+
+    for (run, alive) in alive {
+        if !alive {
+            tokio::fs::remove_file(dir.join(format!("{}.lock", run)))
+                .await
+                .ok();
+        }
+    }
+
+    Ok(())
+}
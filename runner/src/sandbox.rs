@@ -1,23 +1,43 @@
 use std::{
+    collections::HashMap,
     os::unix::prelude::PermissionsExt,
     path::{Path, PathBuf},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use bollard::{
     container::{
-        AttachContainerOptions, AttachContainerResults, CreateContainerOptions, LogOutput,
-        RemoveContainerOptions, StopContainerOptions,
+        AttachContainerOptions, AttachContainerResults, CreateContainerOptions,
+        KillContainerOptions, LogOutput, RemoveContainerOptions, StopContainerOptions,
     },
-    service::{HostConfig, MountTypeEnum, RestartPolicy, RestartPolicyNameEnum},
+    errors::Error as BollardError,
+    service::{ContainerState, HostConfig, MountTypeEnum, RestartPolicy, RestartPolicyNameEnum},
     Docker,
 };
+use chrono::DateTime;
 use futures_util::StreamExt;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use tokio::time::timeout;
+use typster_proto::{BuildVariant, ProgressEvent, StageOutcome, StageResources};
+
+use crate::{
+    lock::FileLock,
+    pipeline::PipelineState,
+    profile::{CacheSettings, Profile, ProfileSettings, Samples, StageSettings},
+    progress::Progress,
+    runs,
+    scheduler::{CoreAllocator, CoreLease},
+    stats::ResourceMonitor,
+};
+
+/// The name of the file in which the pipeline state is persisted
+const STATE_FILE: &str = "state.json";
 
-use crate::profile::{Profile, Samples, Stage};
+/// The name of the file recording where and how the results were produced
+const PROVENANCE_FILE: &str = "provenance.json";
 
 pub struct Sandbox {
     pub id: String,
@@ -30,11 +50,22 @@ pub struct Sandbox {
     pub walltimes: PathBuf,
     pub pgo_data: PathBuf,
     pub pgo_results: PathBuf,
+    pub pgo_walltimes: PathBuf,
 
     pub repository: String,
     pub commit: String,
 
     pub pipe: bool,
+
+    /// The allocator of the CPU cores of the containers, no cores are
+    /// pinned if not set
+    pub cores: Option<Arc<CoreAllocator>>,
+
+    /// Whether the cargo directory is the registry shared between sandboxes
+    pub shared_cargo: bool,
+
+    /// Where the progress of the pipeline is reported, if anywhere
+    pub progress: Option<Progress>,
 }
 
 impl Drop for Sandbox {
@@ -44,8 +75,10 @@ impl Drop for Sandbox {
                 tracing::error!("failed to remove git directory: {}", e);
             }
 
-            if let Err(e) = std::fs::remove_dir_all(&self.cargo) {
-                tracing::error!("failed to remove cargo directory: {}", e);
+            if !self.shared_cargo {
+                if let Err(e) = std::fs::remove_dir_all(&self.cargo) {
+                    tracing::error!("failed to remove cargo directory: {}", e);
+                }
             }
 
             if let Err(e) = std::fs::remove_dir_all(&self.results) {
@@ -63,6 +96,22 @@ impl Drop for Sandbox {
             if let Err(e) = std::fs::remove_dir_all(&self.pgo_results) {
                 tracing::error!("failed to remove pgo-results directory: {}", e);
             }
+
+            if let Err(e) = std::fs::remove_dir_all(&self.pgo_walltimes) {
+                tracing::error!("failed to remove pgo-walltimes directory: {}", e);
+            }
+
+            if let Err(e) = std::fs::remove_file(self.state_file()) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::error!("failed to remove pipeline state: {}", e);
+                }
+            }
+
+            if let Err(e) = std::fs::remove_file(self.provenance_file()) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::error!("failed to remove provenance: {}", e);
+                }
+            }
         }
     }
 }
@@ -100,11 +149,15 @@ impl Sandbox {
 
         let parent = create_directory(root, &id).await?;
         let git = create_directory(&parent, "git").await?;
-        let cargo = create_directory(&parent, "cargo").await?;
+        let cargo = match profile.shared_registry() {
+            Some(cache) => create_directory(&cache.root, "cargo").await?,
+            None => create_directory(&parent, "cargo").await?,
+        };
         let results = create_directory(&parent, "results").await?;
         let walltimes = create_directory(&parent, "walltimes").await?;
         let pgo_data = create_directory(&parent, "pgo-data").await?;
         let pgo_results = create_directory(&parent, "pgo-results").await?;
+        let pgo_walltimes = create_directory(&parent, "pgo-walltimes").await?;
 
         Ok(Self {
             id,
@@ -116,12 +169,94 @@ impl Sandbox {
             walltimes,
             pgo_data,
             pgo_results,
+            pgo_walltimes,
             repository: repository.to_string(),
             commit: commit.to_string(),
             pipe: cfg!(debug_assertions),
+            shared_cargo: profile.shared_registry().is_some(),
+            cores: None,
+            progress: None,
         })
     }
 
+    /// Pins the containers of the sandbox to cores handed out by the allocator
+    pub fn with_cores(mut self, cores: Option<Arc<CoreAllocator>>) -> Self {
+        self.cores = cores;
+        self
+    }
+
+    /// Reports the progress of the pipeline and of its benchmarks
+    pub fn with_progress(mut self, progress: Option<Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Reports a progress event, if anybody listens
+    pub fn report(&self, event: ProgressEvent) {
+        if let Some(progress) = &self.progress {
+            progress.send(event);
+        }
+    }
+
+    /// Creates and starts a container for a stage, on the cores the stage
+    /// is allowed to use
+    async fn create_container(
+        &self,
+        docker: &Docker,
+        name: &str,
+        stage: &StageSettings,
+        env: Vec<&str>,
+        mounts: Vec<Mount>,
+    ) -> anyhow::Result<Container> {
+        let (cpuset, lease) = match (&self.cores, stage.exclusive_cores) {
+            (Some(cores), Some(count)) => {
+                let lease = cores.acquire(count).await?;
+                (Some(lease.cpuset()), Some(lease))
+            }
+            (Some(cores), None) => (Some(cores.shared().to_owned()), None),
+            (None, _) => (None, None),
+        };
+
+        let mut container =
+            create_safe_container(docker, stage, &self.id, name, cpuset, env, mounts).await?;
+        container.lease = lease;
+        container.progress = self.progress.clone();
+
+        Ok(container)
+    }
+
+    /// Opens an existing sandbox, using the repository and commit recorded
+    /// in its pipeline state
+    pub async fn open<P: AsRef<Path>>(
+        profile: &Profile,
+        root: P,
+        id: &str,
+    ) -> anyhow::Result<Self> {
+        let root = root.as_ref();
+        let state = PipelineState::read(root.join(id).join(STATE_FILE))
+            .await?
+            .with_context(|| format!("sandbox `{}` has no pipeline state", id))?;
+
+        Self::new(
+            profile,
+            root,
+            state.repository,
+            state.commit,
+            Some(id.to_owned()),
+        )
+        .await
+    }
+
+    /// Returns the path to the file holding the pipeline state
+    pub fn state_file(&self) -> PathBuf {
+        self.parent.join(STATE_FILE)
+    }
+
+    /// Returns the path to the file recording the provenance of the results
+    pub fn provenance_file(&self) -> PathBuf {
+        self.parent.join(PROVENANCE_FILE)
+    }
+
     /// Returns the ID of the sandbox
     pub fn id(&self) -> &str {
         &self.id
@@ -137,6 +272,22 @@ impl Sandbox {
         &self.walltimes
     }
 
+    /// Returns the path to the results directory of a build variant
+    pub fn results_of(&self, variant: BuildVariant) -> &Path {
+        match variant {
+            BuildVariant::Plain => &self.results,
+            BuildVariant::Pgo => &self.pgo_results,
+        }
+    }
+
+    /// Returns the path to the walltimes directory of a build variant
+    pub fn walltimes_of(&self, variant: BuildVariant) -> &Path {
+        match variant {
+            BuildVariant::Plain => &self.walltimes,
+            BuildVariant::Pgo => &self.pgo_walltimes,
+        }
+    }
+
     /// Returns the path to the cargo directory
     pub fn cargo(&self) -> &Path {
         &self.cargo
@@ -157,37 +308,98 @@ impl Sandbox {
         &self.commit
     }
 
-    /// Clones the repository into the git directory
+    /// Clones the repository into the git directory, going through the
+    /// local mirror of the repository if mirrors are enabled
     pub async fn clone(
         &self,
         profile: &Profile,
         docker: &Docker,
     ) -> anyhow::Result<ContainerOutput> {
-        let stage = &profile.stages.clone;
+        let mut stage = profile.stages.clone.clone();
 
         let repository = format!("REPO_URL={}", self.repository);
         let commit = format!("COMMIT={}", self.commit);
-        let container = create_safe_container(
-            docker,
-            stage,
-            vec![&repository, &commit],
-            vec![Mount {
-                target: "/typster".into(),
-                source: self.git.clone(),
-                read_only: false,
-            }],
-        )
-        .await?;
+        let mut mounts = vec![Mount {
+            target: "/typster".into(),
+            source: self.git.clone(),
+            read_only: false,
+        }];
+
+        let mut mirror_output = None;
+        if let Some(cache) = profile.git_mirrors() {
+            let (mirror, output) = self.update_mirror(profile, docker, cache).await?;
+            if output.exitcode != 0 {
+                tracing::error!("failed to update repository mirror");
+                return Ok(output);
+            }
 
-        let output = container.join(self.pipe).await?;
+            // The commit is in the mirror, the network is no longer needed.
+            stage.networking = false;
+            mounts.push(Mount {
+                target: "/mirror".into(),
+                source: mirror,
+                read_only: true,
+            });
+            mirror_output = Some(output);
+        }
+
+        let container = self
+            .create_container(docker, "clone", &stage, vec![&repository, &commit], mounts)
+            .await?;
+
+        let mut output = container.join(self.pipe).await?;
 
         if output.exitcode != 0 {
             tracing::error!("failed to clone repository");
         }
 
+        if let Some(mirror) = mirror_output {
+            output.stdout.splice(0..0, mirror.stdout);
+            output.stderr.splice(0..0, mirror.stderr);
+            let mut resources = mirror.resources;
+            resources.add(&output.resources);
+            output.resources = resources;
+        }
+
         Ok(output)
     }
 
+    /// Fetches the commit into the bare mirror of the repository, creating
+    /// the mirror if needed, and returns the path to the mirror
+    async fn update_mirror(
+        &self,
+        profile: &Profile,
+        docker: &Docker,
+        cache: &CacheSettings,
+    ) -> anyhow::Result<(PathBuf, ContainerOutput)> {
+        let stage = &profile.stages.clone;
+        let mirror =
+            create_directory(cache.mirrors(), &cache.mirror_name(&self.repository)).await?;
+
+        // Only one sandbox at a time may write into a mirror.
+        let _lock = FileLock::exclusive(mirror.with_extension("lock")).await?;
+
+        let repository = format!("REPO_URL={}", self.repository);
+        let commit = format!("COMMIT={}", self.commit);
+        let container = self
+            .create_container(
+                docker,
+                "mirror",
+                stage,
+                vec![&repository, &commit, "MODE=mirror"],
+                vec![Mount {
+                    target: "/mirror".into(),
+                    source: mirror.clone(),
+                    read_only: false,
+                }],
+            )
+            .await?;
+
+        let output = container.join(self.pipe).await?;
+
+        Ok((mirror, output))
+    }
+
     /// Fetches the crates into the cargo directory
     pub async fn fetch(
         &self,
@@ -196,24 +408,32 @@ impl Sandbox {
     ) -> anyhow::Result<ContainerOutput> {
         let stage = &profile.stages.fetch;
 
-        let container = create_safe_container(
-            docker,
-            stage,
-            vec![],
-            vec![
-                Mount {
-                    target: "/typster".into(),
-                    source: self.git.clone(),
-                    read_only: true,
-                },
-                Mount {
-                    target: "/cargo".into(),
-                    source: self.cargo.clone(),
-                    read_only: false,
-                },
-            ],
-        )
-        .await?;
+        // Only one sandbox at a time may write into the shared registry.
+        let _lock = match profile.shared_registry() {
+            Some(cache) => Some(FileLock::exclusive(cache.registry_lock()).await?),
+            None => None,
+        };
+
+        let container = self
+            .create_container(
+                docker,
+                "fetch",
+                stage,
+                vec![],
+                vec![
+                    Mount {
+                        target: "/typster".into(),
+                        source: self.git.clone(),
+                        read_only: true,
+                    },
+                    Mount {
+                        target: "/cargo".into(),
+                        source: self.cargo.clone(),
+                        read_only: false,
+                    },
+                ],
+            )
+            .await?;
 
         let output = container.join(self.pipe).await?;
 
@@ -232,24 +452,26 @@ impl Sandbox {
     ) -> anyhow::Result<ContainerOutput> {
         let stage = &profile.stages.build;
 
-        let container = create_safe_container(
-            docker,
-            stage,
-            vec![],
-            vec![
-                Mount {
-                    target: "/typster".into(),
-                    source: self.git.clone(),
-                    read_only: false,
-                },
-                Mount {
-                    target: "/cargo".into(),
-                    source: self.cargo.clone(),
-                    read_only: true,
-                },
-            ],
-        )
-        .await?;
+        let container = self
+            .create_container(
+                docker,
+                "build",
+                stage,
+                vec![],
+                vec![
+                    Mount {
+                        target: "/typster".into(),
+                        source: self.git.clone(),
+                        read_only: false,
+                    },
+                    Mount {
+                        target: "/cargo".into(),
+                        source: self.cargo.clone(),
+                        read_only: true,
+                    },
+                ],
+            )
+            .await?;
 
         let output = container.join(self.pipe).await?;
 
@@ -266,17 +488,11 @@ impl Sandbox {
         profile: &Profile,
         docker: &Docker,
         samples: &Samples,
-        main: bool,
+        settings: &ProfileSettings,
         pgo: bool,
     ) -> anyhow::Result<ContainerOutput> {
         let stage = &profile.stages.bench_e2e;
 
-        let settings = if main {
-            &profile.profiles.main
-        } else {
-            &profile.profiles.other
-        };
-
         let interval: Duration = settings.interval.into();
         let sleep: Duration = settings.sleep.into();
         let env_warmups = format!("WARMUPS={}", settings.warmups);
@@ -288,38 +504,40 @@ impl Sandbox {
         let env_freq = format!("FREQUENCY={}", interval.as_micros());
         let env_work = format!("WORK={}", settings.work);
         let env_sleep = format!("SLEEP={}", sleep.as_millis());
-        let container = create_safe_container(
-            docker,
-            stage,
-            vec![
-                &env_warmups,
-                &env_runs,
-                &env_samples,
-                &env_freq,
-                &env_work,
-                &env_sleep,
-            ],
-            vec![
-                Mount {
-                    target: "/typster".into(),
-                    source: self.git.clone(),
-                    read_only: true,
-                },
-                Mount {
-                    target: "/samples".into(),
-                    source: tokio::fs::canonicalize(&samples.root)
-                        .await
-                        .context("failed to canonicalize path")?,
-                    read_only: true,
-                },
-                Mount {
-                    target: "/data".into(),
-                    source: if pgo { self.pgo_results.clone() } else { self.results.clone() },
-                    read_only: false,
-                },
-            ],
-        )
-        .await?;
+        let container = self
+            .create_container(
+                docker,
+                "bench-e2e",
+                stage,
+                vec![
+                    &env_warmups,
+                    &env_runs,
+                    &env_samples,
+                    &env_freq,
+                    &env_work,
+                    &env_sleep,
+                ],
+                vec![
+                    Mount {
+                        target: "/typster".into(),
+                        source: self.git.clone(),
+                        read_only: true,
+                    },
+                    Mount {
+                        target: "/samples".into(),
+                        source: tokio::fs::canonicalize(&samples.root)
+                            .await
+                            .context("failed to canonicalize path")?,
+                        read_only: true,
+                    },
+                    Mount {
+                        target: "/data".into(),
+                        source: if pgo { self.pgo_results.clone() } else { self.results.clone() },
+                        read_only: false,
+                    },
+                ],
+            )
+            .await?;
 
         let output = container.join(self.pipe).await?;
 
@@ -336,16 +554,11 @@ impl Sandbox {
         profile: &Profile,
         docker: &Docker,
         samples: &Samples,
-        main: bool,
+        settings: &ProfileSettings,
+        pgo: bool,
     ) -> anyhow::Result<ContainerOutput> {
         let stage = &profile.stages.bench_walltime;
 
-        let settings = if main {
-            &profile.profiles.main
-        } else {
-            &profile.profiles.other
-        };
-
         let sleep: Duration = settings.sleep.into();
         let env_warmups = format!("WARMUPS={}", settings.warmups);
         let env_runs = format!("RUNS={}", settings.runs);
@@ -355,37 +568,43 @@ impl Sandbox {
         );
         let env_work = format!("WORK={}", settings.work);
         let env_sleep = format!("SLEEP={}", sleep.as_millis());
-        let container = create_safe_container(
-            docker,
-            stage,
-            vec![
-                &env_warmups,
-                &env_runs,
-                &env_samples,
-                &env_work,
-                &env_sleep,
-            ],
-            vec![
-                Mount {
-                    target: "/typster".into(),
-                    source: self.git.clone(),
-                    read_only: true,
-                },
-                Mount {
-                    target: "/samples".into(),
-                    source: tokio::fs::canonicalize(&samples.root)
-                        .await
-                        .context("failed to canonicalize path")?,
-                    read_only: true,
-                },
-                Mount {
-                    target: "/data".into(),
-                    source: self.walltimes.clone(),
-                    read_only: false,
-                },
-            ],
-        )
-        .await?;
+        let container = self
+            .create_container(
+                docker,
+                "bench-walltime",
+                stage,
+                vec![
+                    &env_warmups,
+                    &env_runs,
+                    &env_samples,
+                    &env_work,
+                    &env_sleep,
+                ],
+                vec![
+                    Mount {
+                        target: "/typster".into(),
+                        source: self.git.clone(),
+                        read_only: true,
+                    },
+                    Mount {
+                        target: "/samples".into(),
+                        source: tokio::fs::canonicalize(&samples.root)
+                            .await
+                            .context("failed to canonicalize path")?,
+                        read_only: true,
+                    },
+                    Mount {
+                        target: "/data".into(),
+                        source: if pgo {
+                            self.pgo_walltimes.clone()
+                        } else {
+                            self.walltimes.clone()
+                        },
+                        read_only: false,
+                    },
+                ],
+            )
+            .await?;
 
         let output = container.join(self.pipe).await?;
 
@@ -404,29 +623,31 @@ impl Sandbox {
     ) -> anyhow::Result<ContainerOutput> {
         let stage = &profile.stages.pgo_build_profile;
 
-        let container = create_safe_container(
-            docker,
-            stage,
-            vec![],
-            vec![
-                Mount {
-                    target: "/typster".into(),
-                    source: self.git.clone(),
-                    read_only: false,
-                },
-                Mount {
-                    target: "/cargo".into(),
-                    source: self.cargo.clone(),
-                    read_only: true,
-                },
-                Mount {
-                    target: "/pgo-data".into(),
-                    source: self.pgo_data.clone(),
-                    read_only: false,
-                },
-            ],
-        )
-        .await?;
+        let container = self
+            .create_container(
+                docker,
+                "pgo-build-profile",
+                stage,
+                vec![],
+                vec![
+                    Mount {
+                        target: "/typster".into(),
+                        source: self.git.clone(),
+                        read_only: false,
+                    },
+                    Mount {
+                        target: "/cargo".into(),
+                        source: self.cargo.clone(),
+                        read_only: true,
+                    },
+                    Mount {
+                        target: "/pgo-data".into(),
+                        source: self.pgo_data.clone(),
+                        read_only: false,
+                    },
+                ],
+            )
+            .await?;
 
         let output = container.join(self.pipe).await?;
 
@@ -450,38 +671,40 @@ impl Sandbox {
             "FILE_LIST={}",
             samples.to_training_env().context("no samples found")?
         );
-        let container = create_safe_container(
-            docker,
-            stage,
-            vec![
-                &env_samples,
-            ],
-            vec![
-                Mount {
-                    target: "/typster".into(),
-                    source: self.git.clone(),
-                    read_only: true,
-                },
-                Mount {
-                    target: "/samples".into(),
-                    source: tokio::fs::canonicalize(&samples.root)
-                        .await
-                        .context("failed to canonicalize path")?,
-                    read_only: true,
-                },
-                Mount {
-                    target: "/data".into(),
-                    source: self.walltimes.clone(),
-                    read_only: false,
-                },
-                Mount {
-                    target: "/pgo-data".into(),
-                    source: self.pgo_data.clone(),
-                    read_only: false,
-                }
-            ],
-        )
-        .await?;
+        let container = self
+            .create_container(
+                docker,
+                "pgo-profile",
+                stage,
+                vec![
+                    &env_samples,
+                ],
+                vec![
+                    Mount {
+                        target: "/typster".into(),
+                        source: self.git.clone(),
+                        read_only: true,
+                    },
+                    Mount {
+                        target: "/samples".into(),
+                        source: tokio::fs::canonicalize(&samples.root)
+                            .await
+                            .context("failed to canonicalize path")?,
+                        read_only: true,
+                    },
+                    Mount {
+                        target: "/data".into(),
+                        source: self.walltimes.clone(),
+                        read_only: false,
+                    },
+                    Mount {
+                        target: "/pgo-data".into(),
+                        source: self.pgo_data.clone(),
+                        read_only: false,
+                    }
+                ],
+            )
+            .await?;
 
         let output = container.join(self.pipe).await?;
 
@@ -500,29 +723,31 @@ impl Sandbox {
     ) -> anyhow::Result<ContainerOutput> {
         let stage = &profile.stages.pgo_build;
 
-        let container = create_safe_container(
-            docker,
-            stage,
-            vec![],
-            vec![
-                Mount {
-                    target: "/typster".into(),
-                    source: self.git.clone(),
-                    read_only: false,
-                },
-                Mount {
-                    target: "/cargo".into(),
-                    source: self.cargo.clone(),
-                    read_only: true,
-                },
-                Mount {
-                    target: "/pgo-data".into(),
-                    source: self.pgo_data.clone(),
-                    read_only: false,
-                }
-            ],
-        )
-        .await?;
+        let container = self
+            .create_container(
+                docker,
+                "pgo-build",
+                stage,
+                vec![],
+                vec![
+                    Mount {
+                        target: "/typster".into(),
+                        source: self.git.clone(),
+                        read_only: false,
+                    },
+                    Mount {
+                        target: "/cargo".into(),
+                        source: self.cargo.clone(),
+                        read_only: true,
+                    },
+                    Mount {
+                        target: "/pgo-data".into(),
+                        source: self.pgo_data.clone(),
+                        read_only: false,
+                    }
+                ],
+            )
+            .await?;
 
         let output = container.join(self.pipe).await?;
 
@@ -553,18 +778,57 @@ struct Mount {
 struct Container {
     docker: Docker,
     timeout: Duration,
+    soft_timeout: Duration,
     pub id: String,
     pub stopped: bool,
+
+    /// The cores the container is pinned to, if any
+    cpuset: Option<String>,
+
+    /// The exclusive cores of the container, released once it is dropped
+    lease: Option<CoreLease>,
+
+    /// Samples the resource usage of the container while it runs
+    monitor: Option<ResourceMonitor>,
+    started: Instant,
+
+    /// Where the progress markers printed by the container are reported
+    progress: Option<Progress>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContainerOutput {
     pub stdout: Vec<String>,
     pub stderr: Vec<String>,
     pub exitcode: i64,
+    pub outcome: StageOutcome,
+    #[serde(default)]
+    pub resources: StageResources,
+
+    /// The cores the container was pinned to, if any
+    #[serde(default)]
+    pub cpuset: Option<String>,
+}
+
+impl ContainerOutput {
+    /// Creates the output of a stage that could not run
+    pub fn infra_error(err: &anyhow::Error) -> Self {
+        Self {
+            stdout: Vec::new(),
+            stderr: vec![format!("{:#}", err)],
+            exitcode: -1,
+            outcome: StageOutcome::InfraError(format!("{:#}", err)),
+            resources: StageResources::default(),
+            cpuset: None,
+        }
+    }
 }
 
 impl Container {
+    /// Waits for the container to exit and collects its output.
+    ///
+    /// A container that exceeds its hard timeout is killed, and the output
+    /// gathered until then is returned with a hard timeout outcome.
     async fn join(mut self, pipe: bool) -> anyhow::Result<ContainerOutput> {
         let options = AttachContainerOptions {
             stream: Some(true),
@@ -576,15 +840,19 @@ impl Container {
 
         let docker = self.docker.clone();
         let id = self.id.clone();
-        let collect = tokio::spawn(async move {
+        let progress = self.progress.clone();
+        let started = self.started;
+
+        // The output is collected into buffers owned by this function, such
+        // that whatever was gathered before a hard timeout is kept.
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let collect = async {
             let AttachContainerResults { mut output, .. } = docker
                 .attach_container::<&str>(&id, Some(options))
                 .await
                 .context("failed to attach to container")?;
 
-            let mut stdout = Vec::new();
-            let mut stderr = Vec::new();
-
             while let Some(Ok(output)) = output.next().await {
                 match output {
                     LogOutput::StdErr { message } => {
@@ -613,6 +881,10 @@ impl Container {
                                 tracing::info!(container = ?id, "{}", trimmed);
                             }
 
+                            if let Some(progress) = &progress {
+                                progress.sample_line(trimmed, started.elapsed());
+                            }
+
                             stdout.push(trimmed.to_owned());
                         }
                     }
@@ -620,31 +892,113 @@ impl Container {
                 }
             }
 
-            Ok::<_, anyhow::Error>((stdout, stderr))
-        });
+            // The output stream may end slightly before the container exits,
+            // a non-zero exit code is reported as an error by the wait.
+            match docker.wait_container::<&str>(&id, None).next().await {
+                Some(Err(BollardError::DockerContainerWaitError { .. })) | Some(Ok(_)) | None => {}
+                Some(Err(err)) => return Err(err).context("failed to wait for container"),
+            }
+
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let hard_timeout = match timeout(self.timeout, collect).await {
+            Ok(collected) => {
+                collected.context("failed to collect container output")?;
+                false
+            }
+            Err(_) => {
+                tracing::error!("container exceeded its hard timeout, killing it");
+                if let Err(err) = self
+                    .docker
+                    .kill_container(&self.id, None::<KillContainerOptions<String>>)
+                    .await
+                {
+                    tracing::error!("failed to kill container: {}", err);
+                }
+
+                true
+            }
+        };
 
-        let (stdout, stderr) = timeout(self.timeout, collect)
-            .await
-            .context("timedout while waiting for container output")?
-            .context("failed to join spawned task")?
-            .context("failed to collect container output")?;
+        let inspected = self.docker.inspect_container(&self.id, None).await;
+        let wall_time = inspected
+            .as_ref()
+            .ok()
+            .and_then(|inspect| inspect.state.as_ref())
+            .and_then(run_time)
+            .unwrap_or_else(|| self.started.elapsed());
+
+        let resources = match self.monitor.take() {
+            Some(monitor) => monitor.finish(wall_time).await,
+            None => StageResources {
+                wall_time,
+                ..Default::default()
+            },
+        };
 
-        let exitcode = self
-            .docker
-            .inspect_container(&self.id, None)
-            .await
-            .context("failed to inspect container")?;
+        let state = match inspected {
+            Ok(inspect) => inspect.state.unwrap_or_default(),
+            Err(err) => {
+                return Ok(ContainerOutput {
+                    stdout,
+                    stderr,
+                    exitcode: -1,
+                    outcome: if hard_timeout {
+                        StageOutcome::HardTimeout
+                    } else {
+                        StageOutcome::InfraError(format!("failed to inspect container: {}", err))
+                    },
+                    resources,
+                    cpuset: self.cpuset.clone(),
+                })
+            }
+        };
 
         self.stopped = true;
 
         Ok(ContainerOutput {
             stdout,
             stderr,
-            exitcode: exitcode.state.and_then(|s| s.exit_code).unwrap_or(i64::MAX),
+            exitcode: state.exit_code.unwrap_or(-1),
+            outcome: if hard_timeout {
+                StageOutcome::HardTimeout
+            } else {
+                classify(&state, self.soft_timeout)
+            },
+            resources,
+            cpuset: self.cpuset.clone(),
         })
     }
 }
 
+/// The exit code of `timeout` when the command timed out
+const TIMEOUT_EXIT_CODE: i64 = 124;
+
+/// Classifies how a container ended from its final state
+fn classify(state: &ContainerState, soft_timeout: Duration) -> StageOutcome {
+    if state.oom_killed == Some(true) {
+        return StageOutcome::OomKilled;
+    }
+
+    match state.exit_code {
+        Some(0) => StageOutcome::Success,
+        Some(TIMEOUT_EXIT_CODE) => StageOutcome::SoftTimeout,
+        Some(_) if run_time(state).is_some_and(|time| time >= soft_timeout) => {
+            StageOutcome::SoftTimeout
+        }
+        Some(code) => StageOutcome::ExitCode(code),
+        None => StageOutcome::InfraError("container has no exit code".into()),
+    }
+}
+
+/// Returns how long a container ran for
+fn run_time(state: &ContainerState) -> Option<Duration> {
+    let started = DateTime::parse_from_rfc3339(state.started_at.as_deref()?).ok()?;
+    let finished = DateTime::parse_from_rfc3339(state.finished_at.as_deref()?).ok()?;
+    (finished - started).to_std().ok()
+}
+
 impl Drop for Container {
     fn drop(&mut self) {
         let stopped = self.stopped;
@@ -658,7 +1012,6 @@ impl Drop for Container {
                 {
                     tracing::error!("failed to stop container: {}", err);
                 }
-                return;
             }
 
             if let Err(err) = docker
@@ -682,8 +1035,11 @@ impl Drop for Container {
 #[tracing::instrument(skip(docker))]
 async fn create_safe_container(
     docker: &Docker,
-    stage: &Stage,
-    mut env: Vec<&str>,
+    stage: &StageSettings,
+    sandbox: &str,
+    step: &str,
+    cpuset: Option<String>,
+    env: Vec<&str>,
     mounts: Vec<Mount>,
 ) -> anyhow::Result<Container> {
     /*if stage.networking {
@@ -697,11 +1053,21 @@ async fn create_safe_container(
     let timeout_env = format!("TIMEOUT={}", stage.soft_timeout);
     env.push(&timeout_env);
 
+    // The random suffix keeps names unique across retries and concurrent
+    // runners, the labels let the startup sweep find orphaned containers.
+    let name = format!("typster-{}-{}-{}", sandbox, step, runs::random_id(6));
+    let labels = HashMap::from([
+        (runs::SANDBOX_LABEL, sandbox),
+        (runs::STAGE_LABEL, step),
+        (runs::RUN_LABEL, runs::run_id()),
+    ]);
+
     let secure_config = bollard::container::Config {
         image: Some(&stage.image as &str),
         network_disabled: Some(!stage.networking),
         env: Some(env),
         working_dir: Some("/typster"),
+        labels: Some(labels),
         attach_stderr: Some(true),
         attach_stdout: Some(true),
         tty: Some(true),
@@ -728,12 +1094,11 @@ async fn create_safe_container(
             ),
             memory: Some(stage.memory_limit.as_u64() as _),
             memory_swap: Some(stage.swap_limit.as_u64() as _),
-            cpuset_cpus: stage.cpu_cores.clone(),
+            cpuset_cpus: cpuset.clone(),
             nano_cpus: Some((stage.cpu_limit * 1e9) as _),
             restart_policy: Some(RestartPolicy {
                 name: Some(RestartPolicyNameEnum::NO),
                 maximum_retry_count: Some(0),
-                ..Default::default()
             }),
             cap_drop: Some(vec!["ALL".to_string()]),
             cap_add: Some(vec!["DAC_OVERRIDE".to_string()]),
@@ -752,9 +1117,8 @@ async fn create_safe_container(
         ..Default::default()
     };
 
-    let name = format!("typster-{}", stage.image.replace('/', "-"));
     let options = CreateContainerOptions {
-        name: &name as &str,
+        name: name.as_str(),
         platform: Some("linux/amd64"),
     };
 
@@ -772,10 +1136,18 @@ async fn create_safe_container(
         .await
         .context("failed to start container")?;
 
+    let monitor = ResourceMonitor::start(docker, &container.id);
+
     Ok(Container {
         docker: docker.clone(),
         timeout: stage.hard_timeout.into(),
+        soft_timeout: stage.soft_timeout.into(),
         id: container.id,
         stopped: false,
+        cpuset,
+        lease: None,
+        monitor: Some(monitor),
+        started: Instant::now(),
+        progress: None,
     })
 }
@@ -0,0 +1,239 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context};
+use tokio::sync::Notify;
+
+use crate::profile::{CoreSettings, Profile};
+
+/// Hands out CPU cores to the containers of concurrently running sandboxes.
+///
+/// Stages that need exclusive cores, such as benchmarks, lease a disjoint set
+/// of cores from the exclusive pool and wait until enough of them are free.
+/// Every other stage runs on the shared pool, which never overlaps with the
+/// exclusive one.
+#[derive(Debug)]
+pub struct CoreAllocator {
+    free: Mutex<BTreeSet<usize>>,
+    released: Notify,
+    exclusive: usize,
+    shared: String,
+}
+
+/// A set of exclusive cores, returned to the allocator when dropped.
+#[derive(Debug)]
+pub struct CoreLease {
+    allocator: Arc<CoreAllocator>,
+    cores: Vec<usize>,
+}
+
+impl CoreAllocator {
+    /// Creates the allocator of a profile, if it declares core pools
+    pub fn from_profile(profile: &Profile) -> anyhow::Result<Option<Arc<Self>>> {
+        profile
+            .cores
+            .as_ref()
+            .map(|cores| Self::new(cores).map(Arc::new))
+            .transpose()
+    }
+
+    /// Creates an allocator over the pools, which must be disjoint
+    pub fn new(settings: &CoreSettings) -> anyhow::Result<Self> {
+        let exclusive = parse_cpuset(&settings.exclusive).context("invalid exclusive cores")?;
+        let shared = parse_cpuset(&settings.shared).context("invalid shared cores")?;
+
+        if let Some(core) = exclusive.intersection(&shared).next() {
+            bail!("core {} is in both the exclusive and the shared pool", core);
+        }
+
+        if shared.is_empty() {
+            bail!("the shared pool has no cores");
+        }
+
+        Ok(Self {
+            exclusive: exclusive.len(),
+            free: Mutex::new(exclusive),
+            released: Notify::new(),
+            shared: format_cpuset(shared),
+        })
+    }
+
+    /// Returns the number of cores in the exclusive pool
+    pub fn exclusive(&self) -> usize {
+        self.exclusive
+    }
+
+    /// Returns the cpuset of the shared pool
+    pub fn shared(&self) -> &str {
+        &self.shared
+    }
+
+    /// Waits until `count` exclusive cores are free and leases them
+    pub async fn acquire(self: &Arc<Self>, count: usize) -> anyhow::Result<CoreLease> {
+        if count == 0 || count > self.exclusive {
+            bail!(
+                "cannot lease {} cores from a pool of {} exclusive cores",
+                count,
+                self.exclusive
+            );
+        }
+
+        loop {
+            // Register for wake-ups before checking, such that a release
+            // between the check and the wait is not missed.
+            let released = self.released.notified();
+
+            {
+                let mut free = self.free.lock().expect("core allocator poisoned");
+                if free.len() >= count {
+                    let cores = free.iter().copied().take(count).collect::<Vec<_>>();
+                    for core in &cores {
+                        free.remove(core);
+                    }
+
+                    return Ok(CoreLease {
+                        allocator: self.clone(),
+                        cores,
+                    });
+                }
+            }
+
+            tracing::info!("waiting for {} exclusive cores", count);
+            released.await;
+        }
+    }
+}
+
+impl CoreLease {
+    /// Returns the cpuset of the leased cores
+    pub fn cpuset(&self) -> String {
+        format_cpuset(self.cores.iter().copied())
+    }
+}
+
+impl Drop for CoreLease {
+    fn drop(&mut self) {
+        self.allocator
+            .free
+            .lock()
+            .expect("core allocator poisoned")
+            .extend(self.cores.drain(..));
+
+        self.allocator.released.notify_waiters();
+    }
+}
+
+/// Parses a cpuset in the format used by Docker, such as `1-3,5`
+pub fn parse_cpuset(cpuset: &str) -> anyhow::Result<BTreeSet<usize>> {
+    let mut cores = BTreeSet::new();
+    for part in cpuset.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.trim().parse().context("invalid core number")?;
+                let end: usize = end.trim().parse().context("invalid core number")?;
+                if start > end {
+                    bail!("invalid core range `{}`", part);
+                }
+
+                cores.extend(start..=end);
+            }
+            None => {
+                cores.insert(part.parse().context("invalid core number")?);
+            }
+        }
+    }
+
+    Ok(cores)
+}
+
+fn format_cpuset(cores: impl IntoIterator<Item = usize>) -> String {
+    cores
+        .into_iter()
+        .map(|core| core.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn allocator(exclusive: &str, shared: &str) -> anyhow::Result<Arc<CoreAllocator>> {
+        let settings = CoreSettings {
+            exclusive: exclusive.to_owned(),
+            shared: shared.to_owned(),
+        };
+
+        CoreAllocator::new(&settings).map(Arc::new)
+    }
+
+    fn cores(cpuset: &str) -> Vec<usize> {
+        parse_cpuset(cpuset).unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn parse_cpuset_reads_ranges_and_lists() {
+        assert_eq!(cores("3"), [3]);
+        assert_eq!(cores("0-2,5"), [0, 1, 2, 5]);
+        assert_eq!(cores(" 7-7 , 1 ,2-3"), [1, 2, 3, 7]);
+        assert_eq!(cores("1,,2,"), [1, 2]);
+        assert!(cores("").is_empty());
+    }
+
+    #[test]
+    fn parse_cpuset_rejects_bad_input() {
+        for cpuset in ["a", "1,b", "3-1", "1-", "-1", "1-2-3", "0x1"] {
+            assert!(parse_cpuset(cpuset).is_err(), "{}", cpuset);
+        }
+    }
+
+    #[test]
+    fn new_rejects_overlapping_and_empty_pools() {
+        assert!(allocator("0-3", "3,4").is_err());
+        assert!(allocator("0-3", "").is_err());
+        assert!(allocator("0-3", "x").is_err());
+
+        let allocator = allocator("0-3", "4-5").unwrap();
+        assert_eq!(allocator.exclusive(), 4);
+        assert_eq!(allocator.shared(), "4,5");
+    }
+
+    #[tokio::test]
+    async fn leases_do_not_overlap() {
+        let allocator = allocator("0-3", "4").unwrap();
+        let first = allocator.acquire(2).await.unwrap();
+        let second = allocator.acquire(2).await.unwrap();
+
+        let mut leased = cores(&first.cpuset());
+        leased.extend(cores(&second.cpuset()));
+        leased.sort_unstable();
+        assert_eq!(leased, [0, 1, 2, 3]);
+
+        assert!(allocator.acquire(0).await.is_err());
+        assert!(allocator.acquire(5).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_released_cores() {
+        let allocator = allocator("0-3", "4").unwrap();
+        let first = allocator.acquire(3).await.unwrap();
+
+        let mut waiting = Box::pin(allocator.acquire(2));
+        assert!((&mut waiting).now_or_never().is_none());
+
+        drop(first);
+        let second = waiting.await.unwrap();
+        let leased = cores(&second.cpuset());
+        assert_eq!(leased.len(), 2);
+
+        // The cores of the second lease are not handed out again.
+        let third = allocator.acquire(2).await.unwrap();
+        assert!(cores(&third.cpuset())
+            .iter()
+            .all(|core| !leased.contains(core)));
+    }
+}
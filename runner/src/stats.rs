@@ -0,0 +1,120 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bollard::{
+    container::{MemoryStatsStats, Stats, StatsOptions},
+    Docker,
+};
+use futures_util::StreamExt;
+use tokio::task::JoinHandle;
+use typster_proto::StageResources;
+
+/// How long to wait for the last statistics once a container has exited
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Samples the resource usage of a container through the Docker stats API.
+///
+/// Docker reports cumulative counters roughly once per second, so the last
+/// sample holds the totals and the peak memory is the highest usage seen in
+/// any sample.
+pub struct ResourceMonitor {
+    usage: Arc<Mutex<StageResources>>,
+    task: JoinHandle<()>,
+}
+
+impl ResourceMonitor {
+    /// Starts sampling the statistics of a container
+    pub fn start(docker: &Docker, id: &str) -> Self {
+        let usage = Arc::new(Mutex::new(StageResources::default()));
+
+        let docker = docker.clone();
+        let id = id.to_owned();
+        let shared = usage.clone();
+        let task = tokio::spawn(async move {
+            let mut stats = docker.stats(
+                &id,
+                Some(StatsOptions {
+                    stream: true,
+                    one_shot: false,
+                }),
+            );
+
+            while let Some(sample) = stats.next().await {
+                match sample {
+                    Ok(sample) => record(&mut shared.lock().expect("stats poisoned"), &sample),
+                    Err(err) => {
+                        tracing::debug!(container = ?id, "failed to read stats: {}", err);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self { usage, task }
+    }
+
+    /// Waits for the stream to end after the container exited and returns
+    /// the collected usage, with the wall time of the container
+    pub async fn finish(self, wall_time: Duration) -> StageResources {
+        let Self { usage, mut task } = self;
+        if tokio::time::timeout(DRAIN_TIMEOUT, &mut task)
+            .await
+            .is_err()
+        {
+            task.abort();
+        }
+
+        let mut usage = usage.lock().expect("stats poisoned").clone();
+        usage.wall_time = wall_time;
+        usage
+    }
+}
+
+fn record(usage: &mut StageResources, stats: &Stats) {
+    // Empty samples are sent once the container has stopped.
+    if stats.cpu_stats.cpu_usage.total_usage == 0 {
+        return;
+    }
+
+    usage.cpu_time = Duration::from_nanos(stats.cpu_stats.cpu_usage.total_usage);
+    usage.peak_memory = usage.peak_memory.max(working_set(stats));
+
+    let (mut read, mut write) = (0, 0);
+    for entry in stats
+        .blkio_stats
+        .io_service_bytes_recursive
+        .iter()
+        .flatten()
+    {
+        match entry.op.to_ascii_lowercase().as_str() {
+            "read" => read += entry.value,
+            "write" => write += entry.value,
+            _ => {}
+        }
+    }
+    usage.block_read = read;
+    usage.block_write = write;
+
+    let (mut rx, mut tx) = (0, 0);
+    for network in stats.networks.iter().flat_map(|n| n.values()) {
+        rx += network.rx_bytes;
+        tx += network.tx_bytes;
+    }
+    usage.net_rx = rx;
+    usage.net_tx = tx;
+}
+
+/// Returns the memory usage without the inactive page cache, as reported by
+/// `docker stats`
+fn working_set(stats: &Stats) -> u64 {
+    let usage = stats.memory_stats.usage.unwrap_or_default();
+    let inactive = match &stats.memory_stats.stats {
+        Some(MemoryStatsStats::V1(v1)) => v1.total_inactive_file,
+        Some(MemoryStatsStats::V2(v2)) => v2.inactive_file,
+        None => 0,
+    };
+
+    usage.saturating_sub(inactive)
+}
@@ -0,0 +1,606 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use bollard::Docker;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use tracing::Instrument;
+use typster_proto::{
+    BenchProgress, BenchQuery, BenchResult, BenchSamples, BenchWalltimeSamples, BuildVariant,
+    Encoding, Provenance, Stage, StageOutcome, StageOutput, VariantResult,
+};
+
+use crate::{
+    config::WorkerArgs,
+    database::{Database, RunRole},
+    history::HistoryStore,
+    pipeline::{Pipeline, PipelineReport, StageReport},
+    profile::{BenchOptions, Profile, Samples},
+    progress::Progress,
+    provenance,
+    queue::{amqp, spool::SpoolQueue, Job, JobQueue, ResultSink},
+    results::{sample_name, Metric, SamplingResults},
+    sandbox::{ContainerOutput, Sandbox},
+    scheduler::CoreAllocator,
+};
+
+/// Consumes benchmark queries from a job queue and publishes their results.
+///
+/// The queries come from the embedded spool when one is configured, from the
+/// AMQP broker otherwise, in which case the worker reconnects whenever the
+/// connection to the broker drops.
+pub async fn run(profile: &Profile, docker: &Docker, args: &WorkerArgs) -> anyhow::Result<()> {
+    let worker = Worker {
+        profile,
+        docker,
+        args,
+        pipeline: Pipeline::from_profile(profile).await?,
+        options: args.bench.options(profile)?,
+        cores: CoreAllocator::from_profile(profile)?,
+        history: match &profile.history {
+            Some(settings) => Some(HistoryStore::open(settings).await?),
+            None => None,
+        },
+        database: match &profile.database {
+            Some(settings) => Some(Database::open(&settings.path).await?),
+            None => None,
+        },
+    };
+
+    if let Some(spool) = &args.spool {
+        let queue = SpoolQueue::open(spool).await?;
+        let sink = queue.results();
+        return worker.serve(&queue, &sink).await;
+    }
+
+    loop {
+        let served = match amqp::connect(args).await {
+            Ok((queue, sink)) => worker.serve(&queue, &sink).await,
+            Err(err) => Err(err),
+        };
+
+        match served {
+            Ok(()) => tracing::warn!("connection to AMQP broker closed"),
+            Err(err) => tracing::error!("AMQP worker failed: {:#}", err),
+        }
+
+        tracing::info!("reconnecting in {}", args.reconnect_delay);
+        tokio::time::sleep(args.reconnect_delay.into()).await;
+    }
+}
+
+struct Worker<'a> {
+    profile: &'a Profile,
+    docker: &'a Docker,
+    args: &'a WorkerArgs,
+    pipeline: Pipeline,
+    options: BenchOptions,
+    cores: Option<Arc<CoreAllocator>>,
+    history: Option<HistoryStore>,
+    database: Option<Database>,
+}
+
+/// A commit a query runs on.
+struct Target<'a> {
+    /// The ID the progress and result of the commit are published under
+    id: String,
+    role: RunRole,
+    commit: &'a str,
+}
+
+/// How running a query ended.
+enum Outcome {
+    /// The pipeline ran to completion or failed because of the commit
+    Done(BenchResult),
+
+    /// The pipeline failed because of the infrastructure and should be
+    /// retried
+    Retry(String, Option<BenchResult>),
+}
+
+impl Worker<'_> {
+    /// Runs jobs from the queue until it is closed.
+    ///
+    /// A job is only acknowledged once its result has been published, such
+    /// that a crashing worker never loses it. Jobs that fail because of the
+    /// infrastructure are retried and dead-lettered once they ran out of
+    /// attempts.
+    async fn serve<Q: JobQueue, S: ResultSink>(&self, queue: &Q, sink: &S) -> anyhow::Result<()> {
+        let jobs = self.args.bench.jobs.max(1);
+        let mut running = FuturesUnordered::new();
+
+        // Taking a job is not cancel-safe, so the same intake is polled
+        // until it returns a job, however many jobs finish in between.
+        let mut intake = std::pin::pin!(queue.next());
+        let mut taking = true;
+
+        let error = loop {
+            tokio::select! {
+                job = &mut intake, if taking => {
+                    taking = false;
+                    match job {
+                        Ok(Some(job)) => running.push(self.process(queue, sink, job)),
+                        Ok(None) => break None,
+                        Err(err) => break Some(err),
+                    }
+                }
+                Some(()) = running.next() => {}
+            }
+
+            if !taking && running.len() < jobs {
+                intake.set(queue.next());
+                taking = true;
+            }
+        };
+
+        // Running jobs are allowed to finish, whatever they cannot
+        // acknowledge anymore is handed out again by the queue.
+        while running.next().await.is_some() {}
+
+        match error {
+            Some(err) => Err(err).context("failed to receive queries"),
+            None => Ok(()),
+        }
+    }
+
+    /// Handles a job, releasing it if it could not be handled
+    async fn process<Q: JobQueue, S: ResultSink>(&self, queue: &Q, sink: &S, job: Q::Job) {
+        if let Err(err) = self.handle(queue, sink, &job).await {
+            tracing::error!("failed to handle query: {:#}", err);
+            if let Err(err) = queue.release(&job).await {
+                tracing::error!("failed to release query: {:#}", err);
+            }
+        }
+    }
+
+    /// Runs a query and acknowledges it once its result is published
+    async fn handle<Q: JobQueue, S: ResultSink>(
+        &self,
+        queue: &Q,
+        sink: &S,
+        job: &Q::Job,
+    ) -> anyhow::Result<()> {
+        let attempts = job.attempts();
+        if attempts >= self.args.max_attempts {
+            let reason = format!("failed {} times", attempts);
+            return self.dead_letter(queue, job, &reason).await;
+        }
+
+        let decoded = Encoding::from_content_type(job.content_type())
+            .and_then(|encoding| Ok((encoding, encoding.decode(job.data())?)));
+        let (encoding, bench_query): (_, BenchQuery) = match decoded {
+            Ok(query) => query,
+            Err(err) => {
+                let reason = format!("failed to deserialize bench query: {}", err);
+                return self.dead_letter(queue, job, &reason).await;
+            }
+        };
+
+        // Query IDs name sandboxes and files, and producers are not trusted.
+        if !BenchQuery::is_valid_id(&bench_query.id) {
+            let reason = "invalid bench query: IDs must be 1 to 64 letters, digits, `-` or `_`";
+            return self.dead_letter(queue, job, reason).await;
+        }
+
+        let options = match self.options.for_query(self.profile, &bench_query) {
+            Ok(options) => options,
+            Err(err) => {
+                let reason = format!("invalid bench query: {:#}", err);
+                return self.dead_letter(queue, job, &reason).await;
+            }
+        };
+
+        // The baseline runs first, such that its result is available by the
+        // time the result of the commit is published.
+        let mut targets = Vec::new();
+        if let Some(baseline) = &bench_query.baseline {
+            targets.push(Target {
+                id: format!("{}-baseline", bench_query.id),
+                role: RunRole::Baseline,
+                commit: baseline,
+            });
+        }
+        targets.push(Target {
+            id: bench_query.id.clone(),
+            role: RunRole::Candidate,
+            commit: &bench_query.commit,
+        });
+
+        let mut results = Vec::new();
+        for target in &targets {
+            let span = tracing::info_span!("query", id = %target.id, attempt = attempts + 1);
+            let outcome = self
+                .execute(
+                    &bench_query.repo,
+                    target,
+                    attempts,
+                    &options,
+                    sink,
+                    encoding,
+                )
+                .instrument(span)
+                .await
+                .unwrap_or_else(|err| Outcome::Retry(format!("{:#}", err), None));
+
+            match outcome {
+                Outcome::Done(result) => results.push(result),
+                Outcome::Retry(reason, result) if attempts + 1 >= self.args.max_attempts => {
+                    // Report the last failure such that the query does not
+                    // silently disappear from the results.
+                    results.extend(result);
+                    self.publish(sink, &bench_query, &targets, &results, encoding)
+                        .await?;
+
+                    let reason = format!("failed {} times, last: {}", attempts + 1, reason);
+                    return self.dead_letter(queue, job, &reason).await;
+                }
+                Outcome::Retry(reason, _) => {
+                    tracing::warn!(
+                        "retrying query after attempt {}/{}: {}",
+                        attempts + 1,
+                        self.args.max_attempts,
+                        reason
+                    );
+                    return queue.retry(job, &reason).await;
+                }
+            }
+        }
+
+        self.publish(sink, &bench_query, &targets, &results, encoding)
+            .await?;
+
+        if let (Some(branch), Some(result)) = (&bench_query.branch, results.last()) {
+            self.track(branch, &bench_query, result, sink, encoding)
+                .await;
+        }
+
+        queue.ack(job).await
+    }
+
+    /// Publishes the results of the targets of a query that ran, after
+    /// recording them in the database
+    async fn publish<S: ResultSink>(
+        &self,
+        sink: &S,
+        query: &BenchQuery,
+        targets: &[Target<'_>],
+        results: &[BenchResult],
+        encoding: Encoding,
+    ) -> anyhow::Result<()> {
+        for (target, result) in targets.iter().zip(results) {
+            if let Some(database) = &self.database {
+                // Only the commit itself belongs to the branch, not its
+                // baseline.
+                let branch = query
+                    .branch
+                    .as_deref()
+                    .filter(|_| target.role == RunRole::Candidate);
+                if let Err(err) = database
+                    .record(
+                        &query.id,
+                        target.role,
+                        &query.repo,
+                        target.commit,
+                        branch,
+                        result,
+                    )
+                    .await
+                {
+                    tracing::warn!("{:#}", err);
+                }
+            }
+
+            sink.publish(result, encoding).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the result of a commit to the history of its branch and
+    /// publishes the regressions found in it, which is only logged if it
+    /// fails as the result itself was already published
+    async fn track<S: ResultSink>(
+        &self,
+        branch: &str,
+        query: &BenchQuery,
+        result: &BenchResult,
+        sink: &S,
+        encoding: Encoding,
+    ) {
+        let Some(history) = &self.history else {
+            return;
+        };
+
+        if !matches!(result, BenchResult::Success { .. }) {
+            return;
+        }
+
+        let regressions = match history
+            .record(branch, &query.id, &query.commit, result)
+            .await
+        {
+            Ok(regressions) => regressions,
+            Err(err) => {
+                tracing::warn!("failed to record history of {}: {:#}", branch, err);
+                return;
+            }
+        };
+
+        // Regressions whose alert was not published are reported again
+        // after the next commit of the branch.
+        let mut published = Vec::new();
+        for regression in regressions {
+            let alert = regression.to_alert(branch);
+            tracing::warn!(
+                "regression on {} at {}: {} {} ({}) {:+.2}%",
+                alert.branch,
+                alert.commit,
+                alert.sample,
+                alert.metric,
+                alert.variant,
+                alert.change * 100.0
+            );
+
+            match sink.alert(&alert, encoding).await {
+                Ok(()) => published.push(regression),
+                Err(err) => tracing::warn!("failed to publish alert: {:#}", err),
+            }
+        }
+
+        if let Err(err) = history.mark_alerted(branch, &published).await {
+            tracing::warn!("failed to mark alerts of {}: {:#}", branch, err);
+        }
+    }
+
+    /// Runs the pipeline on a commit, publishing its progress to the sink
+    ///
+    /// Every attempt runs in a new sandbox, such that it never reuses the
+    /// stages of a failed attempt or of an earlier query with the same ID.
+    async fn execute<S: ResultSink>(
+        &self,
+        repo: &str,
+        target: &Target<'_>,
+        attempts: u32,
+        options: &BenchOptions,
+        sink: &S,
+        encoding: Encoding,
+    ) -> anyhow::Result<Outcome> {
+        let sandbox_id = format!("{}-{}", target.id, attempts + 1);
+        let path = self.profile.workdir.join(&sandbox_id);
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            tracing::warn!("removing stale sandbox {}", sandbox_id);
+            tokio::fs::remove_dir_all(&path)
+                .await
+                .with_context(|| format!("failed to remove stale sandbox {}", sandbox_id))?;
+        }
+
+        let (progress, mut events) = Progress::channel(target.id.as_str());
+        let sandbox = Sandbox::new(
+            self.profile,
+            &self.profile.workdir,
+            repo,
+            target.commit,
+            Some(sandbox_id),
+        )
+        .await?
+        .with_cores(self.cores.clone())
+        .with_progress(Some(progress));
+
+        let span = tracing::info_span!("sandbox", id = %sandbox.id);
+        let pipeline = self
+            .pipeline
+            .execute(&sandbox, self.profile, self.docker, options)
+            .instrument(span);
+        tokio::pin!(pipeline);
+
+        // The sandbox keeps the events open, so they are forwarded until the
+        // pipeline finishes and then drained.
+        let report = loop {
+            tokio::select! {
+                report = &mut pipeline => break report?,
+                Some(event) = events.recv() => forward_progress(sink, &event, encoding).await,
+            }
+        };
+
+        while let Ok(event) = events.try_recv() {
+            forward_progress(sink, &event, encoding).await;
+        }
+
+        let infra_error = report
+            .failed
+            .as_deref()
+            .and_then(|stage| report.output(stage))
+            .and_then(|output| match &output.outcome {
+                StageOutcome::InfraError(err) => Some(err.clone()),
+                _ => None,
+            });
+
+        let provenance = provenance::collect(self.docker, self.profile, &report).await;
+        let result = collect_result(&sandbox, &options.samples, report, provenance).await?;
+        Ok(match infra_error {
+            Some(err) => Outcome::Retry(err, Some(result)),
+            None => Outcome::Done(result),
+        })
+    }
+
+    /// Moves a query out of the queue for good
+    async fn dead_letter<Q: JobQueue>(
+        &self,
+        queue: &Q,
+        job: &Q::Job,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        tracing::error!("dead-lettering query: {}", reason);
+        queue.dead_letter(job, reason).await
+    }
+}
+
+/// Publishes a progress event, which is only logged if it fails
+async fn forward_progress<S: ResultSink>(sink: &S, progress: &BenchProgress, encoding: Encoding) {
+    if let Err(err) = sink.progress(progress, encoding).await {
+        tracing::warn!("failed to publish progress: {:#}", err);
+    }
+}
+
+/// Builds the result message of a pipeline run
+pub async fn collect_result(
+    sandbox: &Sandbox,
+    samples: &Samples,
+    report: PipelineReport,
+    provenance: Provenance,
+) -> anyhow::Result<BenchResult> {
+    let output = |kind| report.output_of(kind).cloned().map(StageOutput::from);
+    let failed = report.failed_stage();
+
+    let mut variants = Vec::new();
+    for variant in [BuildVariant::Plain, BuildVariant::Pgo] {
+        let stages = report.outputs_of_variant(variant).collect::<Vec<_>>();
+        if !stages.is_empty() {
+            let result = collect_variant(sandbox, samples, variant, &stages, failed.is_some());
+            variants.push(result.await?);
+        }
+    }
+
+    let summaries = variants.iter().flat_map(VariantResult::summaries).collect();
+
+    // The fields outside of the variants describe the plain build.
+    let plain = |kind| {
+        report
+            .outputs_of_variant(BuildVariant::Plain)
+            .find(|stage| stage.kind == kind)
+            .map(|stage| StageOutput::from(stage.output.clone()))
+    };
+    let (procinfo, walltimes) = variants
+        .iter()
+        .find(|result| result.variant == BuildVariant::Plain)
+        .map(|result| (result.samples.clone(), result.walltimes.clone()))
+        .unwrap_or_default();
+
+    if let Some(failed) = failed {
+        return Ok(BenchResult::Failure {
+            id: sandbox.id.clone(),
+            stage: failed.kind,
+            variant: failed.variant,
+            clone: output(Stage::Clone),
+            fetch: output(Stage::Fetch),
+            build: plain(Stage::Build),
+            bench_e2e: plain(Stage::BenchE2e),
+            bench_walltime: plain(Stage::BenchWalltime),
+            samples: procinfo,
+            walltimes,
+            variants,
+            provenance,
+            summaries,
+        });
+    }
+
+    Ok(BenchResult::Success {
+        id: sandbox.id.clone(),
+        clone: output(Stage::Clone).unwrap_or_default(),
+        fetch: output(Stage::Fetch).unwrap_or_default(),
+        build: plain(Stage::Build).unwrap_or_default(),
+        bench_e2e: plain(Stage::BenchE2e).unwrap_or_default(),
+        bench_walltime: plain(Stage::BenchWalltime).unwrap_or_default(),
+        samples: procinfo,
+        walltimes,
+        variants,
+        provenance,
+        summaries,
+    })
+}
+
+/// Reads the samples of a build variant from the directories its benchmark
+/// stages wrote them to. After a failure, the samples that did not finish
+/// are skipped instead of failing the whole result.
+async fn collect_variant(
+    sandbox: &Sandbox,
+    samples: &Samples,
+    variant: BuildVariant,
+    stages: &[&StageReport],
+    partial: bool,
+) -> anyhow::Result<VariantResult> {
+    let ran = |kind| stages.iter().any(|stage| stage.kind == kind);
+
+    let mut procinfo = Vec::new();
+    if ran(Stage::BenchE2e) {
+        for sample in samples.to_results_file(sandbox.results_of(variant)) {
+            tracing::info!("opening sample file: {}", sample.display());
+            let Some(parsed) = load_sample(&sample, partial).await? else {
+                continue;
+            };
+
+            procinfo.push(to_bench_samples(sample_name(&sample), &parsed)?);
+        }
+    }
+
+    let mut walltimes = Vec::new();
+    if ran(Stage::BenchWalltime) {
+        for sample in samples.to_results_file(sandbox.walltimes_of(variant)) {
+            tracing::info!("opening walltime sample file: {}", sample.display());
+            let Some(parsed) = load_sample(&sample, partial).await? else {
+                continue;
+            };
+
+            walltimes.push(BenchWalltimeSamples {
+                name: sample_name(&sample),
+                walltime: parsed
+                    .metric(Metric::Time)
+                    .context("missing wall time")?
+                    .to_vec(),
+            });
+        }
+    }
+
+    Ok(VariantResult {
+        variant,
+        samples: procinfo,
+        walltimes,
+        stages: stages
+            .iter()
+            .map(|stage| (stage.name.clone(), StageOutput::from(stage.output.clone())))
+            .collect(),
+    })
+}
+
+/// Loads the results of a sample, returning `None` for a partial result
+/// whose sample did not finish
+async fn load_sample(path: &Path, partial: bool) -> anyhow::Result<Option<SamplingResults>> {
+    match SamplingResults::load(path).await {
+        Ok(parsed) => Ok(Some(parsed)),
+        Err(err) if partial => {
+            tracing::warn!("skipping unfinished sample {}: {:#}", path.display(), err);
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Converts the results of a sample into its message representation
+pub fn to_bench_samples(name: String, parsed: &SamplingResults) -> anyhow::Result<BenchSamples> {
+    let metric = |metric, what| {
+        parsed
+            .metric(metric)
+            .map(<[f64]>::to_vec)
+            .with_context(|| format!("missing {}", what))
+    };
+
+    Ok(BenchSamples {
+        name,
+        user_time: metric(Metric::UserCpuTime, "user CPU time")?,
+        system_time: metric(Metric::SystemCpuTime, "system CPU time")?,
+        virtual_memory: metric(Metric::VirtualMemory, "virtual memory")?,
+        resident_memory: metric(Metric::ResidentMemory, "resident memory")?,
+        cpu_percent: metric(Metric::Load, "CPU load")?,
+    })
+}
+
+impl From<ContainerOutput> for StageOutput {
+    fn from(output: ContainerOutput) -> Self {
+        Self {
+            exitcode: output.exitcode as _,
+            stdout: output.stdout,
+            stderr: output.stderr,
+            outcome: output.outcome,
+            resources: output.resources,
+        }
+    }
+}